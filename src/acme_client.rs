@@ -0,0 +1,718 @@
+use arc_swap::ArcSwap;
+use aws_lc_rs::{
+    digest::{SHA256, digest},
+    rand::SystemRandom,
+    signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{HeaderMap, Method, Request};
+use hyper_tls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use rcgen::{CertificateParams, DnType};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{collections::HashMap, fs, sync::Arc, time::Duration};
+use tokio_native_tls::{TlsConnector, native_tls};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use crate::{
+    constants::{ACME_POLL_ATTEMPTS, ACME_POLL_INTERVAL, ACME_REQUEST_TIMEOUT},
+    structs::{AcmeConfig, GenericError},
+};
+
+/**
+ * HTTP-01 key authorizations (token -> key authorization) answered by the
+ * internal server for the http frontends, while the orders are in progress
+ */
+#[derive(Debug, Clone, Default)]
+pub struct AcmeChallenges(Arc<ArcSwap<HashMap<String, String>>>);
+
+impl AcmeChallenges {
+    pub fn insert(&self, token: &str, key_authorization: &str) {
+        self.0.rcu(|challenges| {
+            let mut challenges = HashMap::clone(challenges);
+            challenges.insert(token.to_string(), key_authorization.to_string());
+            challenges
+        });
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.0.rcu(|challenges| {
+            let mut challenges = HashMap::clone(challenges);
+            challenges.remove(token);
+            challenges
+        });
+    }
+
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.0.load().get(token).cloned()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    challenge_type: String,
+    url: String,
+    token: String,
+    error: Option<Value>,
+}
+
+/**
+ * ACME (RFC 8555) client: account, orders validated by HTTP-01 challenges
+ * Requests are signed (ES256) with the account key
+ */
+pub struct AcmeClient {
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    directory: Directory,
+    account_key: EcdsaKeyPair,
+    // account url, once registered
+    kid: Option<String>,
+    nonce: Option<String>,
+    contact: Vec<String>,
+}
+
+impl AcmeClient {
+    /**
+     * account_key: PKCS8 P-256 key
+     */
+    pub async fn new(config: &AcmeConfig, account_key: &[u8]) -> Result<Self, GenericError> {
+        let account_key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, account_key)
+            .map_err(|e| format!("ACME account key: {}", e))?;
+        // Test servers (Pebble...) use their own CA
+        let mut tls = native_tls::TlsConnector::builder();
+        if let Some(ca_certificate) = &config.ca_certificate {
+            let pem = fs::read(ca_certificate)
+                .map_err(|e| format!("ACME ca_certificate {}: {}", ca_certificate, e))?;
+            tls.add_root_certificate(native_tls::Certificate::from_pem(&pem)?);
+        }
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let client = Client::builder(TokioExecutor::new()).build(HttpsConnector::from((
+            http,
+            TlsConnector::from(tls.build()?),
+        )));
+        let mut acme_client = Self {
+            client,
+            directory: Directory::default(),
+            account_key,
+            kid: None,
+            nonce: None,
+            contact: config.contact.clone(),
+        };
+        let (_, body) = acme_client
+            .request(Method::GET, &config.directory_url, None)
+            .await?;
+        acme_client.directory = serde_json::from_slice(&body)
+            .map_err(|e| format!("ACME directory {}: {}", config.directory_url, e))?;
+        Ok(acme_client)
+    }
+
+    /**
+     * Certificate chain and its private key (PEM) for the domain
+     * The token of each authorization is published in challenges until it is validated
+     */
+    pub async fn order_certificate(
+        &mut self,
+        domain: &str,
+        challenges: &AcmeChallenges,
+    ) -> Result<(String, String), GenericError> {
+        if self.kid.is_none() {
+            self.register().await?;
+        }
+        let new_order = self.directory.new_order.clone();
+        let payload = json!({ "identifiers": [{ "type": "dns", "value": domain }] });
+        let (headers, body) = self.post(&new_order, Some(payload)).await?;
+        let order_url = get_location(&headers).ok_or("ACME order: no Location")?;
+        let order: Order = serde_json::from_slice(&body)?;
+
+        for authorization_url in &order.authorizations {
+            self.validate_authorization(authorization_url, challenges)
+                .await?;
+        }
+
+        let certificate_key = rcgen::KeyPair::generate()?;
+        let csr = create_csr(domain, &certificate_key)?;
+        let payload = json!({ "csr": URL_SAFE_NO_PAD.encode(csr) });
+        let (_, body) = self.post(&order.finalize, Some(payload)).await?;
+        let mut order: Order = serde_json::from_slice(&body)?;
+        let mut attempts = 0;
+        while order.status != "valid" {
+            if order.status == "invalid" || attempts >= ACME_POLL_ATTEMPTS {
+                return Err(format!(
+                    "ACME order {}: {} {}",
+                    domain,
+                    order.status,
+                    order.error.unwrap_or_default()
+                )
+                .into());
+            }
+            attempts += 1;
+            tokio::time::sleep(Duration::from_secs(ACME_POLL_INTERVAL)).await;
+            let (_, body) = self.post(&order_url, None).await?;
+            order = serde_json::from_slice(&body)?;
+        }
+        let certificate_url = order.certificate.ok_or("ACME order: no certificate")?;
+        let (_, body) = self.post(&certificate_url, None).await?;
+        Ok((
+            String::from_utf8(body.to_vec())?,
+            certificate_key.serialize_pem(),
+        ))
+    }
+
+    async fn register(&mut self) -> Result<(), GenericError> {
+        let new_account = self.directory.new_account.clone();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": self.contact });
+        let (headers, _) = self.post(&new_account, Some(payload)).await?;
+        self.kid = Some(get_location(&headers).ok_or("ACME account: no Location")?);
+        Ok(())
+    }
+
+    /**
+     * HTTP-01 challenge of a pending authorization, then wait for its validation
+     */
+    async fn validate_authorization(
+        &mut self,
+        authorization_url: &str,
+        challenges: &AcmeChallenges,
+    ) -> Result<(), GenericError> {
+        let (_, body) = self.post(authorization_url, None).await?;
+        let authorization: Authorization = serde_json::from_slice(&body)?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|c| c.challenge_type == "http-01")
+            .ok_or("ACME authorization: no http-01 challenge")?;
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint());
+        challenges.insert(&challenge.token, &key_authorization);
+        let result = self.poll_authorization(authorization_url, &challenge).await;
+        challenges.remove(&challenge.token);
+        result
+    }
+
+    async fn poll_authorization(
+        &mut self,
+        authorization_url: &str,
+        challenge: &Challenge,
+    ) -> Result<(), GenericError> {
+        // Ready to be validated
+        self.post(&challenge.url, Some(json!({}))).await?;
+        for _ in 0..ACME_POLL_ATTEMPTS {
+            let (_, body) = self.post(authorization_url, None).await?;
+            let authorization: Authorization = serde_json::from_slice(&body)?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => {}
+                status => {
+                    let error = authorization
+                        .challenges
+                        .into_iter()
+                        .find(|c| c.token == challenge.token)
+                        .and_then(|c| c.error)
+                        .unwrap_or_default();
+                    return Err(format!("ACME authorization {}: {}", status, error).into());
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(ACME_POLL_INTERVAL)).await;
+        }
+        Err("ACME authorization: timeout".into())
+    }
+
+    /**
+     * JWS signed POST, payload None: POST-as-GET
+     * Retried once with a fresh nonce when the server rejects the current one
+     */
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> Result<(HeaderMap, Bytes), GenericError> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let body = self.sign(url, &nonce, payload.as_ref())?;
+            match self.request(Method::POST, url, Some(body)).await {
+                Err(e) if !retried && e.to_string().contains("badNonce") => retried = true,
+                result => return result,
+            }
+        }
+    }
+
+    async fn new_nonce(&mut self) -> Result<String, GenericError> {
+        let new_nonce = self.directory.new_nonce.clone();
+        self.request(Method::HEAD, &new_nonce, None).await?;
+        self.nonce.take().ok_or_else(|| "ACME: no nonce".into())
+    }
+
+    /**
+     * Replay-Nonce of the response kept for the next request
+     * Error with the problem document (RFC 7807) of the server
+     */
+    async fn request(
+        &mut self,
+        method: Method,
+        url: &str,
+        body: Option<Value>,
+    ) -> Result<(HeaderMap, Bytes), GenericError> {
+        let mut builder = Request::builder().method(method).uri(url);
+        if body.is_some() {
+            builder = builder.header("Content-Type", "application/jose+json");
+        }
+        let request = builder.body(Full::new(Bytes::from(
+            body.map(|body| body.to_string()).unwrap_or_default(),
+        )))?;
+        let response = tokio::time::timeout(
+            Duration::from_secs(ACME_REQUEST_TIMEOUT),
+            self.client.request(request),
+        )
+        .await
+        .map_err(|_| format!("ACME {}: timeout", url))?
+        .map_err(|e| format!("ACME {}: {:?}", url, e))?;
+        let (parts, body) = response.into_parts();
+        if let Some(nonce) = parts
+            .headers
+            .get("Replay-Nonce")
+            .and_then(|n| n.to_str().ok())
+        {
+            self.nonce = Some(nonce.to_string());
+        }
+        let body = body.collect().await?.to_bytes();
+        if !parts.status.is_success() {
+            return Err(format!(
+                "ACME {}: {} {}",
+                url,
+                parts.status,
+                String::from_utf8_lossy(&body)
+            )
+            .into());
+        }
+        Ok((parts.headers, body))
+    }
+
+    /**
+     * Flattened JWS, jwk until the account is registered, then its url (kid)
+     */
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<Value, GenericError> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload
+            .map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string()))
+            .unwrap_or_default();
+        let signature = self
+            .account_key
+            .sign(
+                &SystemRandom::new(),
+                format!("{}.{}", protected, payload).as_bytes(),
+            )
+            .map_err(|_| "ACME: signature failed")?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))
+    }
+
+    /**
+     * Coordinates of the public key (uncompressed point: 0x04, x, y)
+     */
+    fn jwk_coordinates(&self) -> (String, String) {
+        let point = self.account_key.public_key().as_ref();
+        (
+            URL_SAFE_NO_PAD.encode(&point[1..33]),
+            URL_SAFE_NO_PAD.encode(&point[33..65]),
+        )
+    }
+
+    fn jwk(&self) -> Value {
+        let (x, y) = self.jwk_coordinates();
+        json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y })
+    }
+
+    /**
+     * JWK thumbprint (RFC 7638): members in lexicographic order, no whitespace
+     */
+    fn thumbprint(&self) -> String {
+        let (x, y) = self.jwk_coordinates();
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        URL_SAFE_NO_PAD.encode(digest(&SHA256, jwk.as_bytes()))
+    }
+}
+
+fn get_location(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("Location")
+        .and_then(|l| l.to_str().ok())
+        .map(|l| l.to_string())
+}
+
+/**
+ * PKCS#10 certificate request for the domain (common name and subjectAltName)
+ */
+fn create_csr(domain: &str, key: &rcgen::KeyPair) -> Result<Vec<u8>, GenericError> {
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params.distinguished_name.push(DnType::CommonName, domain);
+    Ok(params.serialize_request(key)?.der().to_vec())
+}
+
+/**
+ * notAfter of a DER certificate, unix time
+ */
+pub fn get_certificate_expiry(certificate: &[u8]) -> Option<i64> {
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+    Some(certificate.validity().not_after.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_lc_rs::signature::{ECDSA_P256_SHA256_FIXED, UnparsedPublicKey};
+    use hyper::{Response, StatusCode, body::Incoming, server::conn::http1, service::service_fn};
+    use hyper_util::rt::TokioIo;
+    use rcgen::{CertificateSigningRequestParams, Issuer, SanType, date_time_ymd};
+    use std::{convert::Infallible, sync::Mutex};
+    use tokio::net::TcpListener;
+
+    use crate::certs_manager::CertsManager;
+
+    const DOMAIN: &str = "www.domain.com";
+    const TOKEN: &str = "token-1";
+
+    #[derive(Default)]
+    struct StubState {
+        nonces: Vec<String>,
+        next_nonce: u32,
+        bad_nonce_sent: bool,
+        jwk: Option<Value>,
+        validated: bool,
+        certificate: Option<String>,
+    }
+
+    /**
+     * Minimal ACME server: one account, one order with an http-01 challenge
+     * Signatures and nonces are checked, the challenge is validated by reading
+     * the key authorization the proxy would answer
+     */
+    struct StubAcme {
+        url: String,
+        challenges: AcmeChallenges,
+        ca: Issuer<'static, rcgen::KeyPair>,
+        state: Mutex<StubState>,
+    }
+
+    impl StubAcme {
+        fn json(
+            &self,
+            status: StatusCode,
+            location: Option<&str>,
+            body: Value,
+        ) -> Response<Full<Bytes>> {
+            let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+            *response.status_mut() = status;
+            let mut state = self.state.lock().unwrap();
+            state.next_nonce += 1;
+            let nonce = format!("nonce-{}", state.next_nonce);
+            state.nonces.push(nonce.clone());
+            response
+                .headers_mut()
+                .insert("Replay-Nonce", nonce.parse().unwrap());
+            if let Some(location) = location {
+                let location = format!("{}{}", self.url, location);
+                response
+                    .headers_mut()
+                    .insert("Location", location.parse().unwrap());
+            }
+            response
+        }
+
+        fn order(&self) -> Value {
+            let certificate = self.state.lock().unwrap().certificate.is_some();
+            json!({
+                "status": if certificate { "valid" } else { "pending" },
+                "authorizations": [format!("{}/authz/1", self.url)],
+                "finalize": format!("{}/finalize/1", self.url),
+                "certificate": certificate.then(|| format!("{}/certificate/1", self.url)),
+            })
+        }
+
+        /**
+         * Payload of a JWS, checked against the url, a nonce issued and the account key
+         */
+        fn verify(&self, path: &str, body: &[u8]) -> Result<Value, String> {
+            let jws: Value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+            let decode = |field: &str| {
+                URL_SAFE_NO_PAD
+                    .decode(jws[field].as_str().unwrap_or_default())
+                    .map_err(|e| e.to_string())
+            };
+            let protected: Value =
+                serde_json::from_slice(&decode("protected")?).map_err(|e| e.to_string())?;
+            let payload = decode("payload")?;
+            let mut state = self.state.lock().unwrap();
+            if protected["url"] != format!("{}{}", self.url, path) {
+                return Err(format!("url {}", protected["url"]));
+            }
+            let nonce = protected["nonce"].as_str().unwrap_or_default();
+            let Some(position) = state.nonces.iter().position(|n| n == nonce) else {
+                return Err("badNonce".to_string());
+            };
+            state.nonces.remove(position);
+            let jwk = if path == "/account" {
+                protected["jwk"].clone()
+            } else {
+                if protected["kid"] != format!("{}/account/1", self.url) {
+                    return Err(format!("kid {}", protected["kid"]));
+                }
+                state.jwk.clone().ok_or("no account")?
+            };
+            let coordinate =
+                |name: &str| URL_SAFE_NO_PAD.decode(jwk[name].as_str().unwrap_or_default());
+            let point = [vec![4], coordinate("x").unwrap(), coordinate("y").unwrap()].concat();
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+                .verify(
+                    format!(
+                        "{}.{}",
+                        jws["protected"].as_str().unwrap(),
+                        jws["payload"].as_str().unwrap()
+                    )
+                    .as_bytes(),
+                    &decode("signature")?,
+                )
+                .map_err(|_| "invalid signature")?;
+            state.jwk = Some(jwk);
+            if payload.is_empty() {
+                return Ok(Value::Null);
+            }
+            serde_json::from_slice(&payload).map_err(|e| e.to_string())
+        }
+
+        fn thumbprint(&self) -> String {
+            let state = self.state.lock().unwrap();
+            let jwk = state.jwk.as_ref().unwrap();
+            let jwk = format!(
+                r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+                jwk["x"], jwk["y"]
+            );
+            URL_SAFE_NO_PAD.encode(digest(&SHA256, jwk.as_bytes()))
+        }
+
+        /**
+         * Certificate of the CSR public key, signed by the stub CA
+         */
+        fn issue(&self, csr: &[u8]) -> Result<String, String> {
+            let mut request = CertificateSigningRequestParams::from_der(&csr.to_vec().into())
+                .map_err(|e| e.to_string())?;
+            if request.params.subject_alt_names
+                != vec![SanType::DnsName(DOMAIN.try_into().unwrap())]
+            {
+                return Err("domain not requested".into());
+            }
+            request.params.not_after = date_time_ymd(2099, 12, 31);
+            let certificate = request.signed_by(&self.ca).map_err(|e| e.to_string())?;
+            Ok(certificate.pem())
+        }
+
+        fn handle(&self, path: &str, body: &[u8]) -> Result<Response<Full<Bytes>>, String> {
+            match path {
+                "/directory" => Ok(self.json(
+                    StatusCode::OK,
+                    None,
+                    json!({
+                        "newNonce": format!("{}/nonce", self.url),
+                        "newAccount": format!("{}/account", self.url),
+                        "newOrder": format!("{}/order", self.url),
+                    }),
+                )),
+                "/nonce" => Ok(self.json(StatusCode::OK, None, Value::Null)),
+                _ => {
+                    let payload = self.verify(path, body)?;
+                    match path {
+                        "/account" => {
+                            let mut state = self.state.lock().unwrap();
+                            if !state.bad_nonce_sent {
+                                state.bad_nonce_sent = true;
+                                return Err("badNonce".into());
+                            }
+                            drop(state);
+                            if payload["termsOfServiceAgreed"] != true {
+                                return Err("terms of service".into());
+                            }
+                            Ok(self.json(
+                                StatusCode::CREATED,
+                                Some("/account/1"),
+                                json!({ "status": "valid" }),
+                            ))
+                        }
+                        "/order" => {
+                            if payload["identifiers"][0]["value"] != DOMAIN {
+                                return Err("identifier".into());
+                            }
+                            Ok(self.json(StatusCode::CREATED, Some("/order/1"), self.order()))
+                        }
+                        "/authz/1" => {
+                            let validated = self.state.lock().unwrap().validated;
+                            Ok(self.json(
+                                StatusCode::OK,
+                                None,
+                                json!({
+                                    "status": if validated { "valid" } else { "pending" },
+                                    "challenges": [{
+                                        "type": "http-01",
+                                        "url": format!("{}/challenge/1", self.url),
+                                        "token": TOKEN,
+                                    }],
+                                }),
+                            ))
+                        }
+                        "/challenge/1" => {
+                            let expected = format!("{}.{}", TOKEN, self.thumbprint());
+                            if self.challenges.get(TOKEN) != Some(expected) {
+                                return Err("unauthorized".into());
+                            }
+                            self.state.lock().unwrap().validated = true;
+                            Ok(self.json(StatusCode::OK, None, json!({ "status": "valid" })))
+                        }
+                        "/finalize/1" => {
+                            if !self.state.lock().unwrap().validated {
+                                return Err("orderNotReady".into());
+                            }
+                            let csr = URL_SAFE_NO_PAD
+                                .decode(payload["csr"].as_str().unwrap_or_default())
+                                .map_err(|e| e.to_string())?;
+                            let certificate = self.issue(&csr)?;
+                            self.state.lock().unwrap().certificate = Some(certificate);
+                            Ok(self.json(StatusCode::OK, None, self.order()))
+                        }
+                        "/order/1" => Ok(self.json(StatusCode::OK, None, self.order())),
+                        "/certificate/1" => {
+                            let certificate = self.state.lock().unwrap().certificate.clone();
+                            let mut response = self.json(StatusCode::OK, None, Value::Null);
+                            *response.body_mut() =
+                                Full::new(Bytes::from(certificate.ok_or("no certificate")?));
+                            Ok(response)
+                        }
+                        _ => Err(format!("unknown path {}", path)),
+                    }
+                }
+            }
+        }
+    }
+
+    async fn stub_service(
+        stub: Arc<StubAcme>,
+        req: Request<Incoming>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let path = req.uri().path().to_string();
+        let body = req.into_body().collect().await.unwrap().to_bytes();
+        Ok(stub.handle(&path, &body).unwrap_or_else(|error| {
+            stub.json(
+                StatusCode::BAD_REQUEST,
+                None,
+                json!({ "type": format!("urn:ietf:params:acme:error:{}", error) }),
+            )
+        }))
+    }
+
+    async fn start_stub_acme(challenges: AcmeChallenges) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stub = Arc::new(StubAcme {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            challenges,
+            ca: Issuer::new(
+                CertificateParams::new(Vec::new()).unwrap(),
+                rcgen::KeyPair::generate().unwrap(),
+            ),
+            state: Mutex::new(StubState::default()),
+        });
+        let url = stub.url.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stub = stub.clone();
+                tokio::spawn(async move {
+                    let _ = http1::Builder::new()
+                        .serve_connection(
+                            TokioIo::new(stream),
+                            service_fn(move |req| stub_service(stub.clone(), req)),
+                        )
+                        .await;
+                });
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn certificate_is_ordered() {
+        let challenges = AcmeChallenges::default();
+        let url = start_stub_acme(challenges.clone()).await;
+        let config = AcmeConfig {
+            directory_url: format!("{}/directory", url),
+            contact: vec!["mailto:admin@domain.com".to_string()],
+            renew_before_days: 30,
+            ca_certificate: None,
+        };
+        let account_key = rcgen::KeyPair::generate().unwrap().serialize_der();
+        let mut client = AcmeClient::new(&config, &account_key).await.unwrap();
+        let (certificate_chain, key) = client.order_certificate(DOMAIN, &challenges).await.unwrap();
+        // token published during the validation only
+        assert_eq!(challenges.get(TOKEN), None);
+
+        let certificate = rustls_pemfile::certs(&mut certificate_chain.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        // 2099-12-31
+        assert_eq!(get_certificate_expiry(&certificate), Some(4102358400));
+        // certificate and key loaded (and matched) as a combined pem file
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join(format!("{}.pem", DOMAIN)),
+            format!("{}{}", certificate_chain, key),
+        )
+        .unwrap();
+        assert_eq!(
+            CertsManager::new(dir.path().to_path_buf()).load().unwrap(),
+            1
+        );
+    }
+}
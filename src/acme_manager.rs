@@ -0,0 +1,332 @@
+use arc_swap::ArcSwap;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    sign::CertifiedKey,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    acme_client::{AcmeChallenges, AcmeClient, get_certificate_expiry},
+    certs_manager::CertsManager,
+    constants::{
+        ACME_ACCOUNT_KEY_FILE, ACME_CERTS_DIR, ACME_CHECK_INTERVAL, ACME_RETRY_INTERVAL,
+        CONFIG_WATCH_INTERVAL,
+    },
+    forwarders::acl_matcher::normalize_host,
+    structs::{AcmeConfig, GenericError, ProxyConfig, ProxyProtocols},
+};
+
+/**
+ * Certificates of the hosts of the https frontends, ordered and renewed with ACME
+ * and written to the acme directory of the certs directory (combined pem files)
+ */
+pub struct AcmeManager {
+    config: Arc<ArcSwap<ProxyConfig>>,
+    certs_path: PathBuf,
+    certs_manager: Arc<Mutex<CertsManager>>,
+    challenges: AcmeChallenges,
+    // host -> last failed order, retried after ACME_RETRY_INTERVAL
+    failures: HashMap<String, Instant>,
+}
+
+impl AcmeManager {
+    pub fn new(
+        config: Arc<ArcSwap<ProxyConfig>>,
+        certs_path: PathBuf,
+        certs_manager: Arc<Mutex<CertsManager>>,
+        challenges: AcmeChallenges,
+    ) -> Self {
+        Self {
+            config,
+            certs_path,
+            certs_manager,
+            challenges,
+            failures: HashMap::new(),
+        }
+    }
+
+    /**
+     * Check the certificates at start, when the configuration changes
+     * and every ACME_CHECK_INTERVAL
+     */
+    pub async fn watch(mut self) {
+        let mut watch = tokio::time::interval(Duration::from_secs(CONFIG_WATCH_INTERVAL));
+        let mut checked: Option<(u64, Instant)> = None;
+        loop {
+            watch.tick().await;
+            let version = self.config.load().version;
+            if checked.is_some_and(|(checked_version, at)| {
+                checked_version == version
+                    && at.elapsed() < Duration::from_secs(ACME_CHECK_INTERVAL)
+            }) {
+                continue;
+            }
+            checked = Some((version, Instant::now()));
+            self.check().await;
+        }
+    }
+
+    /**
+     * Order the missing certificates, renew the ones it ordered expiring within renew_before_days
+     */
+    pub async fn check(&mut self) {
+        let config = self.config.load_full();
+        let Some(acme) = &config.acme else {
+            return;
+        };
+        let certificates = self
+            .certs_manager
+            .lock()
+            .await
+            .get_certificates()
+            .load_full();
+        let hosts = get_acme_hosts(&config)
+            .into_iter()
+            .filter(|host| {
+                self.failures.get(host).is_none_or(|failed| {
+                    failed.elapsed() >= Duration::from_secs(ACME_RETRY_INTERVAL)
+                })
+            })
+            .filter(|host| {
+                needs_certificate(
+                    certificates.find(host),
+                    self.get_issued_certificate(host),
+                    acme.renew_before_days,
+                )
+            })
+            .collect::<Vec<_>>();
+        if hosts.is_empty() {
+            return;
+        }
+        let mut client = match self.get_client(acme).await {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("ACME: {}", e);
+                for host in hosts {
+                    self.failures.insert(host, Instant::now());
+                }
+                return;
+            }
+        };
+        for host in hosts {
+            println!("ACME: ordering a certificate for {}", host);
+            match self.order_certificate(&mut client, &host).await {
+                Ok(()) => {
+                    println!("ACME: certificate issued for {}", host);
+                    self.failures.remove(&host);
+                }
+                Err(e) => {
+                    eprintln!("ACME: certificate of {} not issued: {}", host, e);
+                    self.failures.insert(host, Instant::now());
+                }
+            }
+        }
+        // Served without waiting for the certs directory watcher
+        if let Err(e) = self.certs_manager.lock().await.load() {
+            eprintln!("Tls certificates reload failed, keeping current: {}", e);
+        }
+    }
+
+    async fn order_certificate(
+        &self,
+        client: &mut AcmeClient,
+        host: &str,
+    ) -> Result<(), GenericError> {
+        let (certificate_chain, key) = client.order_certificate(host, &self.challenges).await?;
+        let combined_pem = format!("{}{}", certificate_chain, key);
+        let path = self.get_certificate_path(host);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_file(&path, combined_pem.as_bytes())
+    }
+
+    /**
+     * The account key is created with the first order
+     */
+    async fn get_client(&self, acme: &AcmeConfig) -> Result<AcmeClient, GenericError> {
+        let path = self.certs_path.join(ACME_ACCOUNT_KEY_FILE);
+        let account_key = if path.exists() {
+            match rustls_pemfile::private_key(&mut fs::read(&path)?.as_slice())? {
+                Some(PrivateKeyDer::Pkcs8(key)) => key.secret_pkcs8_der().to_vec(),
+                _ => return Err(format!("{}: PKCS8 key expected", path.display()).into()),
+            }
+        } else {
+            let key = rcgen::KeyPair::generate()?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            write_file(&path, key.serialize_pem().as_bytes())?;
+            println!("ACME: account key created: {}", path.display());
+            key.serialize_der()
+        };
+        AcmeClient::new(acme, &account_key).await
+    }
+
+    fn get_certificate_path(&self, host: &str) -> PathBuf {
+        self.certs_path
+            .join(ACME_CERTS_DIR)
+            .join(format!("{}.pem", host))
+    }
+
+    /**
+     * Certificate previously ordered for the host
+     */
+    fn get_issued_certificate(&self, host: &str) -> Option<CertificateDer<'static>> {
+        let pem = fs::read(self.get_certificate_path(host)).ok()?;
+        rustls_pemfile::certs(&mut pem.as_slice()).next()?.ok()
+    }
+}
+
+/**
+ * No certificate served for the host (exact name or wildcard),
+ * or the certificate served was ordered by the proxy and expires soon
+ * The certificates of the operator are never replaced
+ */
+fn needs_certificate(
+    served: Option<Arc<CertifiedKey>>,
+    issued: Option<CertificateDer>,
+    renew_before_days: u64,
+) -> bool {
+    let Some(served) = served else {
+        return true;
+    };
+    let Some(issued) = issued.filter(|issued| served.end_entity_cert().is_ok_and(|c| c == issued))
+    else {
+        return false;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default();
+    get_certificate_expiry(&issued)
+        .is_none_or(|expiry| expiry - now < (renew_before_days * 86400) as i64)
+}
+
+/**
+ * Names of the acls of the https frontends, without the wildcards, regex and ip addresses
+ * The passthrough acls are left to the backends
+ */
+pub fn get_acme_hosts(config: &ProxyConfig) -> BTreeSet<String> {
+    config
+        .frontends
+        .iter()
+        .filter(|f| f.active && f.tls && f.protocol != ProxyProtocols::Tcp)
+        .flat_map(|f| f.acls.iter())
+        .filter(|acl| !acl.passthrough)
+        .map(|acl| normalize_host(&acl.host).to_ascii_lowercase())
+        .filter(|host| {
+            host.contains('.')
+                && host.parse::<IpAddr>().is_err()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        })
+        .collect()
+}
+
+/**
+ * Temporary file + rename, never read half written by the certs directory watcher
+ * The file is only readable by its owner
+ */
+fn write_file(path: &Path, contents: &[u8]) -> Result<(), GenericError> {
+    let dir = path.parent().ok_or("invalid path")?;
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(contents)?;
+    file.as_file().sync_all()?;
+    file.persist(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, date_time_ymd};
+    use rustls::crypto::aws_lc_rs::sign::any_supported_type;
+
+    /**
+     * Certificate of www.domain.com expiring in days
+     */
+    fn certificate(days: u64) -> (CertificateDer<'static>, Arc<CertifiedKey>) {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["www.domain.com".to_string()]).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        params.not_after = date_time_ymd(1970, 1, 1) + now + Duration::from_secs(days * 86400);
+        let certificate = params.self_signed(&key).unwrap().der().clone();
+        let key = any_supported_type(&PrivateKeyDer::Pkcs8(key.serialize_der().into())).unwrap();
+        (
+            certificate.clone(),
+            Arc::new(CertifiedKey::new(vec![certificate], key)),
+        )
+    }
+
+    #[test]
+    fn only_the_certificates_ordered_are_renewed() {
+        let (expiring, expiring_served) = certificate(10);
+        let (valid, valid_served) = certificate(60);
+        // no certificate
+        assert!(needs_certificate(None, None, 30));
+        assert!(needs_certificate(None, Some(expiring.clone()), 30));
+        // ordered by the proxy
+        assert!(needs_certificate(
+            Some(expiring_served.clone()),
+            Some(expiring.clone()),
+            30
+        ));
+        assert!(!needs_certificate(
+            Some(valid_served.clone()),
+            Some(valid.clone()),
+            30
+        ));
+        // of the operator, even expiring or in front of the one ordered
+        assert!(!needs_certificate(Some(expiring_served), None, 30));
+        assert!(!needs_certificate(Some(valid_served), Some(expiring), 30));
+    }
+
+    #[test]
+    fn hosts_of_the_https_acls() {
+        let yaml = r#"
+frontends:
+  - name: "https"
+    protocol: "http"
+    addr: "0.0.0.0"
+    port: 443
+    tls: true
+    active: true
+    acls:
+      - { name: "www", host: "WWW.domain.com:443", backend: "b" }
+      - { name: "api", host: "api.domain.com", backend: "b" }
+      - { name: "api-v2", host: "api.domain.com", path: { type: "prefix", value: "/v2" }, backend: "b" }
+      - { name: "wildcard", host: "*.domain.com", backend: "b" }
+      - { name: "regex", host: "~^app[0-9]+\\.domain\\.com$", backend: "b" }
+      - { name: "ip", host: "192.0.2.1", backend: "b" }
+      - { name: "pass", host: "pass.domain.com", backend: "b", passthrough: true }
+  - name: "http"
+    protocol: "http"
+    addr: "0.0.0.0"
+    port: 80
+    tls: false
+    active: true
+    acls:
+      - { name: "plain", host: "plain.domain.com", backend: "b" }
+pool_backends:
+  - name: "b"
+    servers: []
+pool_servers: []
+"#;
+        let config: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            get_acme_hosts(&config).into_iter().collect::<Vec<_>>(),
+            vec!["api.domain.com", "www.domain.com"]
+        );
+    }
+}
@@ -0,0 +1,538 @@
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    Method, Request, Response, StatusCode, body::Incoming, header::HeaderValue,
+    server::conn::http1, service::service_fn,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::json;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::{net::TcpListener, sync::Mutex};
+
+use crate::{
+    certs_manager::CertsManager,
+    config_manager::{ConfigManager, validate_config},
+    frontends_manager::FrontendsManager,
+    structs::{AclConfig, Backend, BackendServer, FrontEnd, GenericError, ProxyConfig},
+};
+
+/**
+ * Shared by all the api connections
+ */
+pub struct ApiState {
+    pub config_manager: Arc<Mutex<ConfigManager>>,
+    pub frontends_manager: Arc<Mutex<FrontendsManager>>,
+    pub certs_manager: Arc<Mutex<CertsManager>>,
+    pub token: String,
+}
+
+type ApiResult = Result<Response<Full<Bytes>>, (StatusCode, String)>;
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert("Content-Type", HeaderValue::from_static("application/json"));
+    response
+}
+
+fn not_found(kind: &str, name: &str) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        format!("{} {} not found", kind, name),
+    )
+}
+
+fn conflict(kind: &str, name: &str) -> (StatusCode, String) {
+    (
+        StatusCode::CONFLICT,
+        format!("{} {} already exists", kind, name),
+    )
+}
+
+/**
+ * Bearer token check
+ */
+fn is_authorized(req: &Request<Incoming>, token: &str) -> bool {
+    let provided = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or("");
+    // Constant time comparison
+    provided.len() == token.len()
+        && provided
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn read_json<T: DeserializeOwned>(body: Incoming) -> Result<T, (StatusCode, String)> {
+    let bytes = body
+        .collect()
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+        .to_bytes();
+    serde_json::from_slice(&bytes).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/**
+ * Apply a change on a copy of the current config, then validate, optionally
+ * write the config file, swap and refresh listeners
+ * Nothing is live when the validation or the write fails
+ */
+async fn apply_change<F>(state: &ApiState, persist: bool, change: F) -> ApiResult
+where
+    F: FnOnce(&mut ProxyConfig) -> Result<StatusCode, (StatusCode, String)>,
+{
+    let version = {
+        let mut config_manager = state.config_manager.lock().await;
+        let mut config = config_manager.get_config().await.load().as_ref().clone();
+        let status = change(&mut config)?;
+        validate_config(&config).map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?;
+        let version = config_manager
+            .update(config, persist)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        (status, version)
+    };
+    state.frontends_manager.lock().await.apply().await;
+    println!("Configuration changed by API, version: {}", version.1);
+    Ok(json_response(version.0, &json!({ "version": version.1 })))
+}
+
+async fn get_config(state: &ApiState) -> ProxyConfig {
+    let config_manager = state.config_manager.lock().await;
+    config_manager.get_config().await.load().as_ref().clone()
+}
+
+fn find_frontend<'a>(
+    config: &'a mut ProxyConfig,
+    name: &str,
+) -> Result<&'a mut FrontEnd, (StatusCode, String)> {
+    config
+        .frontends
+        .iter_mut()
+        .find(|f| f.name == name)
+        .ok_or_else(|| not_found("Frontend", name))
+}
+
+async fn route(state: &ApiState, req: Request<Incoming>) -> ApiResult {
+    let persist = req
+        .uri()
+        .query()
+        .map(|q| q.split('&').any(|p| p == "persist=true"))
+        .unwrap_or(false);
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let segments = path
+        .trim_matches('/')
+        .split('/')
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    let segments = segments.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    let body = req.into_body();
+
+    match (method, segments.as_slice()) {
+        // Whole config
+        (Method::GET, ["config"]) => Ok(json_response(StatusCode::OK, &get_config(state).await)),
+        // Frontends
+        (Method::GET, ["frontends"]) => Ok(json_response(
+            StatusCode::OK,
+            &get_config(state).await.frontends,
+        )),
+        (Method::GET, ["frontends", name]) => {
+            let mut config = get_config(state).await;
+            let frontend = find_frontend(&mut config, name)?;
+            Ok(json_response(StatusCode::OK, frontend))
+        }
+        (Method::POST, ["frontends"]) => {
+            let frontend: FrontEnd = read_json(body).await?;
+            apply_change(state, persist, |config| {
+                if config.frontends.iter().any(|f| f.name == frontend.name) {
+                    return Err(conflict("Frontend", &frontend.name));
+                }
+                config.frontends.push(frontend);
+                Ok(StatusCode::CREATED)
+            })
+            .await
+        }
+        (Method::PUT, ["frontends", name]) => {
+            let mut frontend: FrontEnd = read_json(body).await?;
+            frontend.name = name.to_string();
+            apply_change(state, persist, |config| {
+                *find_frontend(config, name)? = frontend;
+                Ok(StatusCode::OK)
+            })
+            .await
+        }
+        (Method::DELETE, ["frontends", name]) => {
+            apply_change(state, persist, |config| {
+                find_frontend(config, name)?;
+                config.frontends.retain(|f| f.name != *name);
+                Ok(StatusCode::OK)
+            })
+            .await
+        }
+        // Acls
+        (Method::GET, ["frontends", name, "acls"]) => {
+            let mut config = get_config(state).await;
+            let frontend = find_frontend(&mut config, name)?;
+            Ok(json_response(StatusCode::OK, &frontend.acls))
+        }
+        (Method::GET, ["frontends", name, "acls", acl_name]) => {
+            let mut config = get_config(state).await;
+            let frontend = find_frontend(&mut config, name)?;
+            let acl = frontend
+                .acls
+                .iter()
+                .find(|a| a.name == *acl_name)
+                .ok_or_else(|| not_found("Acl", acl_name))?;
+            Ok(json_response(StatusCode::OK, acl))
+        }
+        (Method::POST, ["frontends", name, "acls"]) => {
+            let acl: AclConfig = read_json(body).await?;
+            apply_change(state, persist, |config| {
+                let frontend = find_frontend(config, name)?;
+                if frontend.acls.iter().any(|a| a.name == acl.name) {
+                    return Err(conflict("Acl", &acl.name));
+                }
+                frontend.acls.push(acl);
+                Ok(StatusCode::CREATED)
+            })
+            .await
+        }
+        (Method::PUT, ["frontends", name, "acls", acl_name]) => {
+            let mut acl: AclConfig = read_json(body).await?;
+            acl.name = acl_name.to_string();
+            apply_change(state, persist, |config| {
+                let frontend = find_frontend(config, name)?;
+                let current = frontend
+                    .acls
+                    .iter_mut()
+                    .find(|a| a.name == *acl_name)
+                    .ok_or_else(|| not_found("Acl", acl_name))?;
+                *current = acl;
+                Ok(StatusCode::OK)
+            })
+            .await
+        }
+        (Method::DELETE, ["frontends", name, "acls", acl_name]) => {
+            apply_change(state, persist, |config| {
+                let frontend = find_frontend(config, name)?;
+                if !frontend.acls.iter().any(|a| a.name == *acl_name) {
+                    return Err(not_found("Acl", acl_name));
+                }
+                frontend.acls.retain(|a| a.name != *acl_name);
+                Ok(StatusCode::OK)
+            })
+            .await
+        }
+        // Backends
+        (Method::GET, ["backends"]) => Ok(json_response(
+            StatusCode::OK,
+            &get_config(state).await.pool_backends,
+        )),
+        (Method::GET, ["backends", name]) => {
+            let config = get_config(state).await;
+            let backend = config
+                .pool_backends
+                .iter()
+                .find(|b| b.name == *name)
+                .ok_or_else(|| not_found("Backend", name))?;
+            Ok(json_response(StatusCode::OK, backend))
+        }
+        (Method::POST, ["backends"]) => {
+            let backend: Backend = read_json(body).await?;
+            apply_change(state, persist, |config| {
+                if config.pool_backends.iter().any(|b| b.name == backend.name) {
+                    return Err(conflict("Backend", &backend.name));
+                }
+                config.pool_backends.push(backend);
+                Ok(StatusCode::CREATED)
+            })
+            .await
+        }
+        (Method::PUT, ["backends", name]) => {
+            let mut backend: Backend = read_json(body).await?;
+            backend.name = name.to_string();
+            apply_change(state, persist, |config| {
+                let current = config
+                    .pool_backends
+                    .iter_mut()
+                    .find(|b| b.name == *name)
+                    .ok_or_else(|| not_found("Backend", name))?;
+                *current = backend;
+                Ok(StatusCode::OK)
+            })
+            .await
+        }
+        (Method::DELETE, ["backends", name]) => {
+            apply_change(state, persist, |config| {
+                if !config.pool_backends.iter().any(|b| b.name == *name) {
+                    return Err(not_found("Backend", name));
+                }
+                config.pool_backends.retain(|b| b.name != *name);
+                Ok(StatusCode::OK)
+            })
+            .await
+        }
+        // Servers
+        (Method::GET, ["servers"]) => Ok(json_response(
+            StatusCode::OK,
+            &get_config(state).await.pool_servers,
+        )),
+        (Method::GET, ["servers", name]) => {
+            let config = get_config(state).await;
+            let server = config
+                .pool_servers
+                .iter()
+                .find(|s| s.name == *name)
+                .ok_or_else(|| not_found("Server", name))?;
+            Ok(json_response(StatusCode::OK, server))
+        }
+        (Method::POST, ["servers"]) => {
+            let server: BackendServer = read_json(body).await?;
+            apply_change(state, persist, |config| {
+                if config.pool_servers.iter().any(|s| s.name == server.name) {
+                    return Err(conflict("Server", &server.name));
+                }
+                config.pool_servers.push(server);
+                Ok(StatusCode::CREATED)
+            })
+            .await
+        }
+        (Method::PUT, ["servers", name]) => {
+            let mut server: BackendServer = read_json(body).await?;
+            server.name = name.to_string();
+            apply_change(state, persist, |config| {
+                let current = config
+                    .pool_servers
+                    .iter_mut()
+                    .find(|s| s.name == *name)
+                    .ok_or_else(|| not_found("Server", name))?;
+                *current = server;
+                Ok(StatusCode::OK)
+            })
+            .await
+        }
+        (Method::DELETE, ["servers", name]) => {
+            apply_change(state, persist, |config| {
+                if !config.pool_servers.iter().any(|s| s.name == *name) {
+                    return Err(not_found("Server", name));
+                }
+                config.pool_servers.retain(|s| s.name != *name);
+                Ok(StatusCode::OK)
+            })
+            .await
+        }
+        // Enable/disable one server
+        (Method::POST, ["servers", name, action @ ("enable" | "disable")]) => {
+            let active = *action == "enable";
+            apply_change(state, persist, |config| {
+                let server = config
+                    .pool_servers
+                    .iter_mut()
+                    .find(|s| s.name == *name)
+                    .ok_or_else(|| not_found("Server", name))?;
+                server.active = active;
+                Ok(StatusCode::OK)
+            })
+            .await
+        }
+        // Reload the certs directory
+        (Method::POST, ["certificates", "reload"]) => {
+            let count = state
+                .certs_manager
+                .lock()
+                .await
+                .load()
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            println!("Tls certificates reloaded by API: {} certificate(s)", count);
+            Ok(json_response(
+                StatusCode::OK,
+                &json!({ "certificates": count }),
+            ))
+        }
+        _ => Err((StatusCode::NOT_FOUND, format!("Route not found: {}", path))),
+    }
+}
+
+async fn api_service(
+    state: Arc<ApiState>,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if !is_authorized(&req, &state.token) {
+        return Ok(json_response(
+            StatusCode::UNAUTHORIZED,
+            &json!({ "error": "Unauthorized" }),
+        ));
+    }
+    match route(&state, req).await {
+        Ok(response) => Ok(response),
+        Err((status, error)) => Ok(json_response(status, &json!({ "error": error }))),
+    }
+}
+
+pub async fn api_rest(state: Arc<ApiState>, addr: SocketAddr) -> Result<(), GenericError> {
+    println!("API Rest listener is listening on: {}", addr);
+
+    let listener = TcpListener::bind(addr).await?;
+
+    loop {
+        match listener.accept().await {
+            Ok((tcp, peer_addr)) => {
+                let io = TokioIo::new(tcp);
+                let state = state.clone();
+
+                tokio::task::spawn(async move {
+                    let svc = service_fn(move |req| api_service(state.clone(), req));
+                    if let Err(err) = http1::Builder::new()
+                        .timer(TokioTimer::new())
+                        .serve_connection(io, svc)
+                        .await
+                    {
+                        eprintln!("[api listener error] from: {} - {:?}", peer_addr, err);
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("[api listener ACCEPT ERROR] {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_manager::Args;
+    use clap::Parser;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    const TOKEN: &str = "secret";
+    const CONFIG: &str = r#"
+frontends: []
+pool_backends: []
+pool_servers: []
+"#;
+
+    /**
+     * Api listening on a free port, config file in config_dir
+     */
+    async fn start_api(config_dir: &std::path::Path) -> (u16, Arc<ApiState>) {
+        let config_path = config_dir.join("config.yaml");
+        std::fs::write(&config_path, CONFIG).unwrap();
+        let mut config_manager = ConfigManager::new(Args::parse_from([
+            "http_reverse_proxy",
+            "-c",
+            config_path.to_str().unwrap(),
+            "-t",
+            config_dir.to_str().unwrap(),
+        ]));
+        config_manager.load().await.unwrap();
+        let config = config_manager.get_config().await;
+        let certs_manager = CertsManager::new(config_dir.to_path_buf());
+        let state = Arc::new(ApiState {
+            config_manager: Arc::new(Mutex::new(config_manager)),
+            frontends_manager: Arc::new(Mutex::new(FrontendsManager::new(
+                config,
+                certs_manager.get_certificates(),
+            ))),
+            certs_manager: Arc::new(Mutex::new(certs_manager)),
+            token: TOKEN.to_string(),
+        });
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        tokio::spawn(api_rest(
+            state.clone(),
+            SocketAddr::from(([127, 0, 0, 1], port)),
+        ));
+        (port, state)
+    }
+
+    /**
+     * Status code and body of the response
+     */
+    async fn call(
+        port: u16,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> (u16, String) {
+        let mut stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let authorization = token
+            .map(|token| format!("Authorization: Bearer {}\r\n", token))
+            .unwrap_or_default();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\n{authorization}\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_string())
+            .unwrap_or_default();
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn missing_or_wrong_token_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, _) = start_api(dir.path()).await;
+        assert_eq!(call(port, "GET", "/config", None, "").await.0, 401);
+        assert_eq!(
+            call(port, "GET", "/config", Some("secreT"), "").await.0,
+            401
+        );
+        assert_eq!(
+            call(port, "GET", "/config", Some("secret2"), "").await.0,
+            401
+        );
+        assert_eq!(call(port, "GET", "/config", Some(TOKEN), "").await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn change_not_written_is_not_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().join("config");
+        std::fs::create_dir(&config_dir).unwrap();
+        let (port, state) = start_api(&config_dir).await;
+        let server = r#"{"name": "s0", "host": "127.0.0.1", "port": 8080,
+            "protocol": "http", "tls": false, "active": true}"#;
+
+        // The config directory is gone: the file can't be written
+        std::fs::remove_dir_all(&config_dir).unwrap();
+        let (status, _) = call(port, "POST", "/servers?persist=true", Some(TOKEN), server).await;
+        assert_eq!(status, 500);
+        let config = get_config(&state).await;
+        assert!(config.pool_servers.is_empty());
+        assert_eq!(config.version, 0);
+
+        // Applied without persist
+        let (status, body) = call(port, "POST", "/servers", Some(TOKEN), server).await;
+        assert_eq!(status, 201, "{}", body);
+        assert_eq!(get_config(&state).await.pool_servers.len(), 1);
+    }
+}
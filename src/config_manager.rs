@@ -1,10 +1,23 @@
 use arc_swap::ArcSwap;
 use clap::Parser;
-use std::{env, fs::File, path::PathBuf, sync::Arc};
+use hyper::{Uri, header::HeaderValue};
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, File},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::SystemTime,
+};
 
 use crate::{
-    constants::{DEFAULT_CONFIG_PATH, DEFAULT_TLS_CERT_PATH},
-    structs::{GenericError, ProxyConfig},
+    constants::{DEFAULT_API_ADDR, DEFAULT_CONFIG_PATH, DEFAULT_TLS_CERT_PATH, MAX_SERVER_WEIGHT},
+    forwarders::{
+        acl_matcher::{HostMatcher, PathMatcher, RequestMatcher, parse_ip_ranges},
+        header_rules::validate_header_rule,
+    },
+    structs::{GenericError, HashSource, ProxyConfig, ProxyProtocols},
 };
 
 // Define the CLI arguments structure
@@ -26,12 +39,20 @@ pub struct Args {
     //Listening API Rest addr
     #[arg(short = 'a', long)]
     api_addr: Option<String>,
+
+    // API Rest bearer token
+    #[arg(long)]
+    api_token: Option<String>,
 }
 
 pub struct ConfigManager {
     config_path: PathBuf,
     tls_certs_path: PathBuf,
+    api_port: Option<u16>,
+    api_addr: String,
+    api_token: Option<String>,
     config: Option<Arc<ArcSwap<ProxyConfig>>>,
+    last_modified: Option<SystemTime>,
 }
 
 impl ConfigManager {
@@ -46,27 +67,375 @@ impl ConfigManager {
             .or_else(|| env::var("DEFAULT_TLS_CERT_PATH").ok().map(PathBuf::from))
             .unwrap_or_else(|| PathBuf::from(DEFAULT_TLS_CERT_PATH));
 
+        let api_port = clap_args.api_port.or_else(|| {
+            env::var("API_PORT")
+                .ok()
+                .and_then(|port| port.parse::<u16>().ok())
+        });
+
+        let api_addr = clap_args
+            .api_addr
+            .or_else(|| env::var("API_ADDR").ok())
+            .unwrap_or_else(|| DEFAULT_API_ADDR.to_string());
+
+        let api_token = clap_args.api_token.or_else(|| env::var("API_TOKEN").ok());
+
         Self {
             config_path,
             tls_certs_path,
+            api_port,
+            api_addr,
+            api_token,
             config: None,
+            last_modified: None,
         }
     }
 
     pub async fn load(&mut self) -> Result<(), GenericError> {
         println!("Configuration file path: {:?}", self.config_path.clone());
-        let file = File::open(self.config_path.clone())?;
-
-        let config: ProxyConfig = serde_yaml::from_reader(file)?;
+        self.last_modified = self.get_config_modified();
+        let config = self.read_config()?;
         self.config = Some(Arc::new(ArcSwap::new(Arc::new(config))));
         Ok(())
     }
 
+    /**
+     * Read and validate the config file, then swap it into the shared ArcSwap
+     * The previous configuration is kept if the new one is invalid
+     */
+    pub async fn reload(&mut self) -> Result<u64, GenericError> {
+        self.last_modified = self.get_config_modified();
+        let config = self.read_config()?;
+        self.update(config, false).await
+    }
+
+    /**
+     * Validate a new configuration, write it to the config file when persist is set,
+     * then swap it (version bumped): a configuration that can't be written is never live
+     */
+    pub async fn update(
+        &mut self,
+        mut config: ProxyConfig,
+        persist: bool,
+    ) -> Result<u64, GenericError> {
+        validate_config(&config)?;
+        let current = self.get_config().await;
+        config.version = current.load().version + 1;
+        if persist {
+            self.save(&config)?;
+        }
+        let version = config.version;
+        current.store(Arc::new(config));
+        Ok(version)
+    }
+
+    /**
+     * Write the configuration to the config file
+     * (temporary file + rename, the file is never seen half written)
+     */
+    fn save(&mut self, config: &ProxyConfig) -> Result<(), GenericError> {
+        let dir = self
+            .config_path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("."));
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_yaml::to_writer(&mut file, config)?;
+        file.as_file().sync_all()?;
+        file.persist(&self.config_path)?;
+        // Our own change, not to be reloaded by the watcher
+        self.last_modified = self.get_config_modified();
+        Ok(())
+    }
+
+    /**
+     * true when the config file mtime changed since the last (re)load
+     */
+    pub fn is_config_modified(&self) -> bool {
+        let modified = self.get_config_modified();
+        modified.is_some() && modified != self.last_modified
+    }
+
+    fn get_config_modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.config_path)
+            .and_then(|m| m.modified())
+            .ok()
+    }
+
+    fn read_config(&self) -> Result<ProxyConfig, GenericError> {
+        let file = File::open(self.config_path.clone())?;
+        let config: ProxyConfig = serde_yaml::from_reader(file)?;
+        validate_config(&config)?;
+        Ok(config)
+    }
+
     pub async fn get_config_tls_certs_path(&self) -> PathBuf {
         self.tls_certs_path.clone()
     }
 
+    /**
+     * Api Rest listening address, None when no port is set
+     */
+    pub async fn get_api_addr(&self) -> Result<Option<SocketAddr>, GenericError> {
+        match self.api_port {
+            None => Ok(None),
+            Some(port) => {
+                let ipaddr = self
+                    .api_addr
+                    .parse::<IpAddr>()
+                    .map_err(|e| format!("Invalid API addr '{}': {}", self.api_addr, e))?;
+                Ok(Some(SocketAddr::from((ipaddr, port))))
+            }
+        }
+    }
+
+    pub async fn get_api_token(&self) -> Option<String> {
+        self.api_token.clone()
+    }
+
     pub async fn get_config(&self) -> Arc<ArcSwap<ProxyConfig>> {
         self.config.clone().unwrap()
     }
 }
+
+/**
+ * Check config consistency: names are unique and every reference
+ * (frontend -> acl -> backend -> servers) can be resolved
+ */
+pub fn validate_config(config: &ProxyConfig) -> Result<(), GenericError> {
+    let mut frontend_names = HashSet::new();
+    for frontend in &config.frontends {
+        if !frontend_names.insert(&frontend.name) {
+            return Err(format!("Duplicate frontend name: {}", frontend.name).into());
+        }
+        frontend.addr.parse::<IpAddr>().map_err(|e| {
+            format!(
+                "Frontend {}: invalid addr '{}': {}",
+                frontend.name, frontend.addr, e
+            )
+        })?;
+        if let Some(default_backend) = &frontend.default_backend
+            && !config
+                .pool_backends
+                .iter()
+                .any(|b| &b.name == default_backend)
+        {
+            return Err(format!(
+                "Frontend {}: default_backend references unknown backend {}",
+                frontend.name, default_backend
+            )
+            .into());
+        }
+        if !matches!(frontend.unknown_host_status, 404 | 421) {
+            return Err(format!(
+                "Frontend {}: unknown_host_status must be 404 or 421",
+                frontend.name
+            )
+            .into());
+        }
+        if let Some(https_redirect) = &frontend.https_redirect
+            && !config
+                .frontends
+                .iter()
+                .any(|f| &f.name == https_redirect && f.tls)
+        {
+            return Err(format!(
+                "Frontend {}: https_redirect references unknown or non tls frontend {}",
+                frontend.name, https_redirect
+            )
+            .into());
+        }
+        if frontend.protocol == ProxyProtocols::Tcp
+            && (frontend.default_backend.is_none()
+                || !frontend.acls.is_empty()
+                || frontend.https_redirect.is_some())
+        {
+            return Err(format!(
+                "Frontend {}: tcp frontends need a default_backend, acls and https_redirect are http only",
+                frontend.name
+            )
+            .into());
+        }
+        parse_ip_ranges(&frontend.trusted_proxies)
+            .map_err(|e| format!("Frontend {}: trusted_proxies: {}", frontend.name, e))?;
+        for acl in &frontend.acls {
+            if acl.passthrough
+                && (!frontend.tls
+                    || acl.path.is_some()
+                    || acl.when.is_some()
+                    || acl.redirect.is_some()
+                    || acl.headers.is_some()
+                    || acl.antibot.unwrap_or(false))
+            {
+                return Err(format!(
+                    "Frontend {}: acl {}: passthrough needs a tls frontend, without path, when, redirect, headers or antibot",
+                    frontend.name, acl.name
+                )
+                .into());
+            }
+            for rule in acl
+                .headers
+                .iter()
+                .flat_map(|h| h.request.iter().chain(&h.response))
+            {
+                validate_header_rule(rule)
+                    .map_err(|e| format!("Frontend {}: acl {}: {}", frontend.name, acl.name, e))?;
+            }
+            if let Some(redirect) = &acl.redirect {
+                if !matches!(redirect.status, 301 | 302 | 307 | 308) {
+                    return Err(format!(
+                        "Frontend {}: acl {} redirect status must be 301, 302, 307 or 308",
+                        frontend.name, acl.name
+                    )
+                    .into());
+                }
+                HeaderValue::from_str(&redirect.to).map_err(|e| {
+                    format!(
+                        "Frontend {}: acl {} invalid redirect '{}': {}",
+                        frontend.name, acl.name, redirect.to, e
+                    )
+                })?;
+            } else if !config.pool_backends.iter().any(|b| b.name == acl.backend) {
+                return Err(format!(
+                    "Frontend {}: acl {} references unknown backend {}",
+                    frontend.name, acl.name, acl.backend
+                )
+                .into());
+            }
+            HostMatcher::new(&acl.host).map_err(|e| {
+                format!(
+                    "Frontend {}: acl {} invalid host regex '{}': {}",
+                    frontend.name, acl.name, acl.host, e
+                )
+            })?;
+            if let Some(when) = &acl.when {
+                RequestMatcher::new(when).map_err(|e| {
+                    format!(
+                        "Frontend {}: acl {} invalid condition: {}",
+                        frontend.name, acl.name, e
+                    )
+                })?;
+            }
+            if let Some(path) = &acl.path {
+                PathMatcher::new(path).map_err(|e| {
+                    format!(
+                        "Frontend {}: acl {} invalid path regex '{}': {}",
+                        frontend.name, acl.name, path.value, e
+                    )
+                })?;
+            }
+        }
+    }
+    let mut backend_names = HashSet::new();
+    for backend in &config.pool_backends {
+        if !backend_names.insert(&backend.name) {
+            return Err(format!("Duplicate backend name: {}", backend.name).into());
+        }
+        for rule in backend
+            .headers
+            .iter()
+            .flat_map(|h| h.request.iter().chain(&h.response))
+        {
+            validate_header_rule(rule).map_err(|e| format!("Backend {}: {}", backend.name, e))?;
+        }
+        if let Some(health_check) = &backend.health_check
+            && (health_check.interval == 0
+                || health_check.timeout == 0
+                || health_check.rise == 0
+                || health_check.fall == 0)
+        {
+            return Err(format!(
+                "Backend {}: health_check interval, timeout, rise and fall must be greater than 0",
+                backend.name
+            )
+            .into());
+        }
+        if let Some(hash_key) = &backend.hash_key
+            && matches!(hash_key.source, HashSource::Header | HashSource::Cookie)
+            && hash_key.name.as_deref().unwrap_or("").is_empty()
+        {
+            return Err(format!(
+                "Backend {}: hash_key name is mandatory for header and cookie sources",
+                backend.name
+            )
+            .into());
+        }
+        if let Some(passive_check) = &backend.passive_check
+            && passive_check.enabled
+            && (passive_check.max_failures == 0 || passive_check.ejection_time == 0)
+        {
+            return Err(format!(
+                "Backend {}: passive_check max_failures and ejection_time must be greater than 0",
+                backend.name
+            )
+            .into());
+        }
+        for server in &backend.servers {
+            if !config.pool_servers.iter().any(|s| &s.name == server) {
+                return Err(format!("Backend {}: unknown server {}", backend.name, server).into());
+            }
+        }
+    }
+    let mut server_names = HashSet::new();
+    for server in &config.pool_servers {
+        if !server_names.insert(&server.name) {
+            return Err(format!("Duplicate server name: {}", server.name).into());
+        }
+        if server.weight > MAX_SERVER_WEIGHT {
+            return Err(format!(
+                "Server {}: weight must be at most {}",
+                server.name, MAX_SERVER_WEIGHT
+            )
+            .into());
+        }
+    }
+    if let Some(acme) = &config.acme {
+        let directory_url = acme.directory_url.parse::<Uri>().map_err(|e| {
+            format!(
+                "Acme: invalid directory_url '{}': {}",
+                acme.directory_url, e
+            )
+        })?;
+        if !matches!(directory_url.scheme_str(), Some("http" | "https")) {
+            return Err(format!(
+                "Acme: directory_url '{}' must be an http(s) url",
+                acme.directory_url
+            )
+            .into());
+        }
+        if acme.renew_before_days == 0 {
+            return Err("Acme: renew_before_days must be greater than 0".into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_backend_config(weight: u32) -> ProxyConfig {
+        let yaml = format!(
+            r#"
+frontends: []
+pool_backends:
+  - name: "b"
+    balance: "hash"
+    hash_key: {{ source: "ip" }}
+    servers: ["s0"]
+pool_servers:
+  - {{ name: "s0", host: "127.0.0.1", port: 8080, protocol: "http", tls: false, active: true, weight: {weight} }}
+"#
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn too_heavy_servers_are_rejected() {
+        assert!(validate_config(&hash_backend_config(MAX_SERVER_WEIGHT)).is_ok());
+        assert!(validate_config(&hash_backend_config(MAX_SERVER_WEIGHT + 1)).is_err());
+        // would overflow the hash ring size
+        assert!(validate_config(&hash_backend_config(u32::MAX)).is_err());
+    }
+}
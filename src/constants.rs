@@ -1,14 +1,55 @@
 // Backend
 pub const POOL_MAX_IDLE_PER_HOST: usize = 250;
 pub const POOL_IDLE_TIMEOUT: u64 = 60;
+// tcp frontends, seconds to connect to the server
+pub const TCP_BACKEND_CONNECT_TIMEOUT: u64 = 5;
+pub const DEFAULT_SERVER_WEIGHT: u32 = 1;
+pub const MAX_SERVER_WEIGHT: u32 = 1000;
+// Consistent hash ring points per unit of weight
+pub const HASH_RING_POINTS_PER_WEIGHT: u32 = 100;
+
+// Health checks default
+pub const DEFAULT_HEALTH_CHECK_PATH: &str = "/";
+pub const DEFAULT_HEALTH_CHECK_EXPECTED_STATUS: u16 = 200;
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 5;
+pub const DEFAULT_HEALTH_CHECK_TIMEOUT: u64 = 2;
+pub const DEFAULT_HEALTH_CHECK_RISE: u32 = 2;
+pub const DEFAULT_HEALTH_CHECK_FALL: u32 = 3;
+pub const DEFAULT_PASSIVE_CHECK_MAX_FAILURES: u32 = 3;
+pub const DEFAULT_PASSIVE_CHECK_EJECTION_TIME: u64 = 10;
+pub const DEFAULT_PASSIVE_CHECK_MAX_EJECTION_TIME: u64 = 300;
+// Consecutive successes forgiving one ejection of the passive check
+pub const PASSIVE_CHECK_SUCCESSES_PER_EJECTION: u32 = 20;
 
 // Config default
 pub const DEFAULT_CONFIG_PATH: &str = "/etc/http_reverse_proxy/config.yaml";
 pub const DEFAULT_TLS_CERT_PATH: &str = "/etc/http_reverse_proxy/certs";
+pub const DEFAULT_API_ADDR: &str = "127.0.0.1";
+// Config file watch interval (seconds)
+pub const CONFIG_WATCH_INTERVAL: u64 = 5;
+pub const CERTS_WATCH_INTERVAL: u64 = 5;
 
 // Http header
 pub const HTTP_HEADER_X_FORWARDED_FOR: &str = "X-Forwarded-For";
+pub const HTTP_HEADER_X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+pub const HTTP_HEADER_X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+pub const HTTP_HEADER_X_FORWARDED_PORT: &str = "X-Forwarded-Port";
 pub const HTTP_HEADER_X_REAL_IP: &str = "X-Real-IP";
+pub const HTTP_HEADER_REFRESH: &str = "Refresh";
+// removed in both directions (RFC 9110 7.6.1), with the ones listed by Connection
+pub const HTTP_HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+// received-by of the Via header
+pub const VIA_PSEUDONYM: &str = "http_reverse_proxy";
 
 //internal ports
 pub const HTTP_INTERNAL_SERVER: u16 = 2201;
@@ -16,12 +57,62 @@ pub const HTTP_INTERNAL_SERVER: u16 = 2201;
 // antibot
 pub const ANTIBOT_COOKIE_NAME: &str = "antibot";
 
+// unknown host (no acl and no default backend): 404 Not Found | 421 Misdirected Request
+pub const DEFAULT_UNKNOWN_HOST_STATUS: u16 = 404;
+// name of the acl built for the default backend
+pub const DEFAULT_BACKEND_ACL_NAME: &str = "default_backend";
+
+// redirects
+pub const DEFAULT_REDIRECT_STATUS: u16 = 302;
+// http -> https, permanent and keeping the method
+pub const HTTPS_REDIRECT_STATUS: u16 = 308;
+
+// PROXY protocol
+pub const PROXY_PROTOCOL_V1_MAX_LENGTH: usize = 107;
+pub const PROXY_PROTOCOL_V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+// seconds to receive the header of an inbound connection
+pub const PROXY_PROTOCOL_READ_TIMEOUT: u64 = 5;
+
+// SNI passthrough
+// seconds to receive the TLS ClientHello
+pub const TLS_CLIENT_HELLO_READ_TIMEOUT: u64 = 5;
+// records of the ClientHello, 16K each
+pub const TLS_CLIENT_HELLO_MAX_RECORDS: usize = 4;
+
+// ACME
+// certificates ordered by the proxy and account key, in the certs directory
+pub const ACME_CERTS_DIR: &str = "acme";
+pub const ACME_ACCOUNT_KEY_FILE: &str = "acme/account.key";
+pub const DEFAULT_ACME_RENEW_BEFORE_DAYS: u64 = 30;
+// seconds between the checks of the certificates to order or renew
+pub const ACME_CHECK_INTERVAL: u64 = 3600;
+// seconds before a failed order is retried (rate limits of the ACME servers)
+pub const ACME_RETRY_INTERVAL: u64 = 3600;
+// authorizations and orders polling: seconds between requests, attempts
+pub const ACME_POLL_INTERVAL: u64 = 2;
+pub const ACME_POLL_ATTEMPTS: u32 = 30;
+pub const ACME_REQUEST_TIMEOUT: u64 = 30;
+// HTTP-01 challenges, followed by the token
+pub const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+// sticky sessions
+pub const DEFAULT_STICKY_COOKIE_NAME: &str = "http_reverse_proxy_server";
+
 // Routes
 //--> antibot
 pub const INTERNAL_ROUTE_ANTIBOT: &str = "_internal_server/antibot";
 //--> internal errors
 pub const INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE: &str =
     "_internal_server/no_backend_server_available";
+// followed by /{http status}
+pub const INTERNAL_ROUTE_ERROR_UNKNOWN_HOST: &str = "_internal_server/unknown_host";
+//--> redirects, followed by /{http status}/{location, base64url}
+pub const INTERNAL_ROUTE_REDIRECT: &str = "_internal_server/redirect";
+
+//--> ACME HTTP-01 challenges, followed by /{token}
+pub const INTERNAL_ROUTE_ACME_CHALLENGE: &str = "_internal_server/acme_challenge";
 
 // Websocket
 pub const INTERNAL_ROUTE_MAKE_WEBSOCKET: &str = "_internal_server/websocket";
@@ -0,0 +1,243 @@
+use hyper::{Method, header::HeaderName};
+use ipnet::IpNet;
+use regex::{Regex, RegexBuilder};
+use std::net::IpAddr;
+
+use crate::structs::{AclCondition, PathMatch, PathMatchType};
+
+use super::{forwarder_helper::get_cookie_value, servers_tracker::BalanceContext};
+
+/**
+ * Compiled acl path condition
+ */
+#[derive(Debug, Clone)]
+pub enum PathMatcher {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl PathMatcher {
+    pub fn new(path_match: &PathMatch) -> Result<Self, regex::Error> {
+        Ok(match path_match.match_type {
+            PathMatchType::Exact => PathMatcher::Exact(path_match.value.clone()),
+            PathMatchType::Prefix => PathMatcher::Prefix(path_match.value.clone()),
+            PathMatchType::Regex => PathMatcher::Regex(Regex::new(&path_match.value)?),
+        })
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        match self {
+            PathMatcher::Exact(value) => path == value,
+            // whole segments: /api matches /api and /api/users, not /apiary
+            PathMatcher::Prefix(value) => path.strip_prefix(value.as_str()).is_some_and(|rest| {
+                rest.is_empty() || rest.starts_with('/') || value.ends_with('/')
+            }),
+            PathMatcher::Regex(regex) => regex.is_match(path),
+        }
+    }
+
+    /**
+     * Acls evaluation order: exact, longest prefix first, regex then no path
+     */
+    pub fn get_priority(matcher: &Option<PathMatcher>) -> (u8, usize) {
+        match matcher {
+            Some(PathMatcher::Exact(_)) => (0, 0),
+            Some(PathMatcher::Prefix(value)) => (1, usize::MAX - value.len()),
+            Some(PathMatcher::Regex(_)) => (2, 0),
+            None => (3, 0),
+        }
+    }
+
+    /**
+     * Replace the matched part of the path with rewrite
+     */
+    pub fn rewrite(&self, path: &str, rewrite: &str) -> String {
+        let new_path = match self {
+            PathMatcher::Exact(_) => rewrite.to_string(),
+            PathMatcher::Prefix(value) => {
+                let rest = path.strip_prefix(value.as_str()).unwrap_or(path);
+                let rewrite = rewrite.trim_end_matches('/');
+                if rest.is_empty() || rest.starts_with('/') {
+                    format!("{}{}", rewrite, rest)
+                } else {
+                    format!("{}/{}", rewrite, rest)
+                }
+            }
+            PathMatcher::Regex(regex) => regex.replace(path, rewrite).to_string(),
+        };
+        if new_path.starts_with('/') {
+            new_path
+        } else {
+            format!("/{}", new_path)
+        }
+    }
+}
+
+/**
+ * Compiled acl host condition
+ * "www.example.com", "*.example.com" (any subdomain) or "~regex"
+ */
+#[derive(Debug, Clone)]
+pub enum HostMatcher {
+    Exact(String),
+    // ".example.com"
+    Wildcard(String),
+    Regex(Regex),
+}
+
+impl HostMatcher {
+    pub fn new(host: &str) -> Result<Self, regex::Error> {
+        if let Some(pattern) = host.strip_prefix('~') {
+            return Ok(HostMatcher::Regex(
+                RegexBuilder::new(pattern).case_insensitive(true).build()?,
+            ));
+        }
+        let host = normalize_host(host);
+        Ok(match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => HostMatcher::Wildcard(suffix.to_string()),
+            _ => HostMatcher::Exact(host),
+        })
+    }
+
+    /**
+     * host must be normalized (see normalize_host)
+     */
+    pub fn is_match(&self, host: &str) -> bool {
+        match self {
+            HostMatcher::Exact(value) => host == value,
+            HostMatcher::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+            HostMatcher::Regex(regex) => regex.is_match(host),
+        }
+    }
+
+    /**
+     * Hosts evaluation order: exact, longest wildcard first, then regex
+     */
+    pub fn get_priority(&self) -> (u8, usize) {
+        match self {
+            HostMatcher::Exact(_) => (0, 0),
+            HostMatcher::Wildcard(suffix) => (1, usize::MAX - suffix.len()),
+            HostMatcher::Regex(_) => (2, 0),
+        }
+    }
+}
+
+/**
+ * Lowercase host without the :port suffix and the trailing dot
+ */
+pub fn normalize_host(host: &str) -> String {
+    let host = match host.strip_prefix('[') {
+        // ipv6 [::1]:8080
+        Some(ipv6) => ipv6.split(']').next().unwrap_or(ipv6),
+        None => match host.rsplit_once(':') {
+            Some((name, port))
+                if !name.contains(':') && port.chars().all(|c| c.is_ascii_digit()) =>
+            {
+                name
+            }
+            _ => host,
+        },
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/**
+ * Compiled acl request condition
+ */
+#[derive(Debug, Clone)]
+pub enum RequestMatcher {
+    All(Vec<RequestMatcher>),
+    Any(Vec<RequestMatcher>),
+    Not(Box<RequestMatcher>),
+    Method(Vec<Method>),
+    Header(HeaderName, Option<String>),
+    Query(String, Option<String>),
+    Cookie(String, Option<String>),
+    Source(Vec<IpNet>),
+}
+
+impl RequestMatcher {
+    pub fn new(condition: &AclCondition) -> Result<Self, String> {
+        let compile_all = |conditions: &Vec<AclCondition>| {
+            conditions
+                .iter()
+                .map(RequestMatcher::new)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(match condition {
+            AclCondition::All(conditions) => RequestMatcher::All(compile_all(conditions)?),
+            AclCondition::Any(conditions) => RequestMatcher::Any(compile_all(conditions)?),
+            AclCondition::Not(condition) => {
+                RequestMatcher::Not(Box::new(RequestMatcher::new(condition)?))
+            }
+            AclCondition::Method(methods) => RequestMatcher::Method(
+                methods
+                    .iter()
+                    .map(|method| {
+                        Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                            .map_err(|e| format!("invalid method '{}': {}", method, e))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            AclCondition::Header(header) => RequestMatcher::Header(
+                HeaderName::from_bytes(header.name.as_bytes())
+                    .map_err(|e| format!("invalid header name '{}': {}", header.name, e))?,
+                header.value.clone(),
+            ),
+            AclCondition::Query(query) => {
+                RequestMatcher::Query(query.name.clone(), query.value.clone())
+            }
+            AclCondition::Cookie(cookie) => {
+                RequestMatcher::Cookie(cookie.name.clone(), cookie.value.clone())
+            }
+            AclCondition::Source(ranges) => RequestMatcher::Source(parse_ip_ranges(ranges)?),
+        })
+    }
+
+    pub fn is_match(&self, context: &BalanceContext) -> bool {
+        let is_value_match =
+            |expected: &Option<String>, value: &str| expected.as_ref().is_none_or(|v| v == value);
+        match self {
+            RequestMatcher::All(matchers) => matchers.iter().all(|m| m.is_match(context)),
+            RequestMatcher::Any(matchers) => matchers.iter().any(|m| m.is_match(context)),
+            RequestMatcher::Not(matcher) => !matcher.is_match(context),
+            RequestMatcher::Method(methods) => methods.contains(context.method),
+            RequestMatcher::Header(name, value) => context
+                .headers
+                .get_all(name)
+                .iter()
+                .filter_map(|h| h.to_str().ok())
+                .any(|h| is_value_match(value, h)),
+            // raw (not decoded) query parameters
+            RequestMatcher::Query(name, value) => context
+                .uri
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .map(|param| param.split_once('=').unwrap_or((param, "")))
+                .any(|(n, v)| n == name && is_value_match(value, v)),
+            RequestMatcher::Cookie(name, value) => get_cookie_value(context.headers, name)
+                .is_some_and(|cookie| is_value_match(value, &cookie)),
+            RequestMatcher::Source(ranges) => {
+                let client_ip = context.client_ip.to_canonical();
+                ranges.iter().any(|range| range.contains(&client_ip))
+            }
+        }
+    }
+}
+
+/**
+ * CIDR ranges, a single address is a /32 (or /128)
+ */
+pub fn parse_ip_ranges(ranges: &[String]) -> Result<Vec<IpNet>, String> {
+    ranges
+        .iter()
+        .map(|range| {
+            range
+                .parse::<IpNet>()
+                .or_else(|_| range.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|e| format!("invalid address range '{}': {}", range, e))
+        })
+        .collect()
+}
@@ -0,0 +1,197 @@
+use arc_swap::ArcSwap;
+use std::{collections::HashMap, sync::Arc};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+/**
+ * Loaded certificates, replaced as a whole when the certs directory is reloaded
+ */
+#[derive(Debug, Default, Clone)]
+pub struct Certificates {
+    // name -> certificate
+    certificates: HashMap<String, Arc<CertifiedKey>>,
+    // parent domain of *.domain.com -> certificate
+    wildcards: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    /**
+     * name: "www.domain.com" or "*.domain.com"
+     */
+    pub fn add(&mut self, name: &str, certificate: Arc<CertifiedKey>) {
+        let name = name.to_ascii_lowercase();
+        match name.strip_prefix("*.") {
+            Some(parent) => self.wildcards.insert(parent.to_string(), certificate),
+            None => self.certificates.insert(name, certificate),
+        };
+    }
+
+    /**
+     * Certificate loaded under this name
+     */
+    pub fn get(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        match name.strip_prefix("*.") {
+            Some(parent) => self.wildcards.get(parent).cloned(),
+            None => self.certificates.get(&name).cloned(),
+        }
+    }
+
+    /**
+     * Exact name, then wildcard (*.domain.com covers one level of subdomain)
+     */
+    pub fn find(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = server_name.trim_end_matches('.').to_ascii_lowercase();
+        self.certificates.get(&name).cloned().or_else(|| {
+            name.split_once('.')
+                .and_then(|(_, parent)| self.wildcards.get(parent).cloned())
+        })
+    }
+}
+
+/**
+ * Certificate of the TLS server name, then the default certificate (no SNI, unknown name)
+ * Certificates are shared by all the tls frontends and swapped on reload
+ */
+#[derive(Debug)]
+pub struct CertResolver {
+    certificates: Arc<ArcSwap<Certificates>>,
+    default_certificate: Option<String>,
+}
+
+impl CertResolver {
+    pub fn new(certificates: Arc<ArcSwap<Certificates>>) -> Self {
+        Self {
+            certificates,
+            default_certificate: None,
+        }
+    }
+
+    /**
+     * Certificate loaded under this name becomes the default one
+     * Looked up on each handshake: not loaded yet (typo, ACME order pending),
+     * clients without SNI or with an unknown name are refused until it is
+     */
+    pub fn set_default(&mut self, name: &str) {
+        self.default_certificate = Some(name.to_ascii_lowercase());
+    }
+
+    pub fn get_certificate(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.load();
+        server_name
+            .and_then(|name| certificates.find(name))
+            .or_else(|| {
+                self.default_certificate
+                    .as_ref()
+                    .and_then(|name| certificates.get(name))
+            })
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.get_certificate(client_hello.server_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{
+        SignatureAlgorithm, SignatureScheme,
+        pki_types::CertificateDer,
+        sign::{Signer, SigningKey},
+    };
+
+    #[derive(Debug)]
+    struct TestKey;
+
+    impl SigningKey for TestKey {
+        fn choose_scheme(&self, _offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+            None
+        }
+
+        fn algorithm(&self) -> SignatureAlgorithm {
+            SignatureAlgorithm::ECDSA
+        }
+    }
+
+    /**
+     * Certificates told apart by their (fake) der content
+     */
+    fn certificate(name: &str) -> Arc<CertifiedKey> {
+        Arc::new(CertifiedKey::new(
+            vec![CertificateDer::from(name.as_bytes().to_vec())],
+            Arc::new(TestKey),
+        ))
+    }
+
+    fn resolved(resolver: &CertResolver, server_name: Option<&str>) -> Option<String> {
+        resolver
+            .get_certificate(server_name)
+            .map(|certificate| String::from_utf8(certificate.cert[0].to_vec()).unwrap())
+    }
+
+    #[test]
+    fn exact_wildcard_then_default() {
+        let mut certificates = Certificates::default();
+        certificates.add("www.domain.com", certificate("www"));
+        certificates.add("*.domain.com", certificate("wildcard"));
+        let mut resolver = CertResolver::new(Arc::new(ArcSwap::from_pointee(certificates)));
+        assert_eq!(
+            resolved(&resolver, Some("WWW.domain.com")).as_deref(),
+            Some("www")
+        );
+        assert_eq!(
+            resolved(&resolver, Some("shop.domain.com")).as_deref(),
+            Some("wildcard")
+        );
+        // one level only
+        assert_eq!(resolved(&resolver, Some("a.shop.domain.com")), None);
+        assert_eq!(resolved(&resolver, Some("domain.com")), None);
+        assert_eq!(resolved(&resolver, None), None);
+
+        // not loaded: SNI only
+        resolver.set_default("other.domain.com");
+        assert_eq!(resolved(&resolver, None), None);
+        assert_eq!(
+            resolved(&resolver, Some("www.domain.com")).as_deref(),
+            Some("www")
+        );
+        resolver.set_default("*.domain.com");
+        assert_eq!(resolved(&resolver, None).as_deref(), Some("wildcard"));
+        assert_eq!(
+            resolved(&resolver, Some("example.com")).as_deref(),
+            Some("wildcard")
+        );
+    }
+
+    #[test]
+    fn reloaded_certificates_are_served() {
+        let mut certificates = Certificates::default();
+        certificates.add("www.domain.com", certificate("www"));
+        let certificates = Arc::new(ArcSwap::from_pointee(certificates));
+        let mut resolver = CertResolver::new(certificates.clone());
+        resolver.set_default("www.domain.com");
+        assert_eq!(resolved(&resolver, None).as_deref(), Some("www"));
+
+        let mut renewed = Certificates::default();
+        renewed.add("www.domain.com", certificate("renewed"));
+        certificates.store(Arc::new(renewed));
+        assert_eq!(
+            resolved(&resolver, Some("www.domain.com")).as_deref(),
+            Some("renewed")
+        );
+        assert_eq!(resolved(&resolver, None).as_deref(), Some("renewed"));
+        // default removed from the directory, then loaded again
+        certificates.store(Arc::new(Certificates::default()));
+        assert_eq!(resolved(&resolver, None), None);
+        let mut renewed = Certificates::default();
+        renewed.add("www.domain.com", certificate("reloaded"));
+        certificates.store(Arc::new(renewed));
+        assert_eq!(resolved(&resolver, None).as_deref(), Some("reloaded"));
+    }
+}
@@ -0,0 +1,319 @@
+use hyper::{
+    HeaderMap, Version,
+    header::{CONNECTION, FORWARDED, HOST, HeaderName, HeaderValue, VIA},
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::constants::{
+    HTTP_HEADER_X_FORWARDED_FOR, HTTP_HEADER_X_FORWARDED_HOST, HTTP_HEADER_X_FORWARDED_PORT,
+    HTTP_HEADER_X_FORWARDED_PROTO, HTTP_HEADER_X_REAL_IP, HTTP_HOP_BY_HOP_HEADERS, VIA_PSEUDONYM,
+};
+
+/**
+ * Connection seen by the proxy, written to the forwarding headers
+ */
+pub struct ForwardedInfo<'a> {
+    pub peer_ip: IpAddr,
+    // peer_ip, or the client found in the chain of a trusted proxy
+    pub client_ip: IpAddr,
+    // peer is a trusted proxy: incoming forwarding headers are kept
+    pub trusted: bool,
+    pub proto: &'a str,
+    // Host header of the request, port included
+    pub host: &'a str,
+    // port of the frontend
+    pub port: u16,
+}
+
+pub fn is_trusted_proxy(ip: IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    let ip = ip.to_canonical();
+    trusted_proxies.iter().any(|range| range.contains(&ip))
+}
+
+/**
+ * Real client ip: the chain (X-Forwarded-For, or else Forwarded) is read from the right
+ * while the hops are trusted proxies, an unreadable hop stops the walk
+ */
+pub fn get_client_ip(peer_ip: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    if !is_trusted_proxy(peer_ip, trusted_proxies) {
+        return peer_ip;
+    }
+    let mut chain = get_header_list(headers, HTTP_HEADER_X_FORWARDED_FOR);
+    if chain.is_empty() {
+        chain = get_header_list(headers, FORWARDED.as_str())
+            .iter()
+            .filter_map(|element| get_forwarded_for(element))
+            .collect();
+    }
+    let mut client_ip = peer_ip;
+    for hop in chain.iter().rev() {
+        match parse_node(hop) {
+            Some(ip) => {
+                client_ip = ip;
+                if !is_trusted_proxy(ip, trusted_proxies) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    client_ip
+}
+
+/**
+ * Headers of an untrusted peer are dropped, then the peer is appended to the chains
+ * X-Forwarded-Proto, -Host and -Port of a trusted proxy are kept
+ */
+pub fn set_forwarded_headers(headers: &mut HeaderMap, info: &ForwardedInfo) {
+    if !info.trusted {
+        for name in [
+            HTTP_HEADER_X_FORWARDED_FOR,
+            HTTP_HEADER_X_FORWARDED_PROTO,
+            HTTP_HEADER_X_FORWARDED_HOST,
+            HTTP_HEADER_X_FORWARDED_PORT,
+            HTTP_HEADER_X_REAL_IP,
+            FORWARDED.as_str(),
+        ] {
+            headers.remove(name);
+        }
+    }
+    let peer_ip = info.peer_ip.to_canonical();
+
+    let mut forwarded_for = get_header_list(headers, HTTP_HEADER_X_FORWARDED_FOR);
+    forwarded_for.push(peer_ip.to_string());
+    set_header(
+        headers,
+        HTTP_HEADER_X_FORWARDED_FOR,
+        &forwarded_for.join(", "),
+    );
+
+    let mut forwarded = get_header_list(headers, FORWARDED.as_str());
+    let mut element = format!("for={}", quote_forwarded_value(&format_node(peer_ip)));
+    if !info.host.is_empty() {
+        element.push_str(&format!(";host={}", quote_forwarded_value(info.host)));
+    }
+    element.push_str(&format!(";proto={}", info.proto));
+    forwarded.push(element);
+    set_header(headers, FORWARDED.as_str(), &forwarded.join(", "));
+
+    if !headers.contains_key(HTTP_HEADER_X_FORWARDED_PROTO) {
+        set_header(headers, HTTP_HEADER_X_FORWARDED_PROTO, info.proto);
+    }
+    if !headers.contains_key(HTTP_HEADER_X_FORWARDED_HOST) && !info.host.is_empty() {
+        set_header(headers, HTTP_HEADER_X_FORWARDED_HOST, info.host);
+    }
+    if !headers.contains_key(HTTP_HEADER_X_FORWARDED_PORT) {
+        set_header(
+            headers,
+            HTTP_HEADER_X_FORWARDED_PORT,
+            &info.port.to_string(),
+        );
+    }
+    set_header(
+        headers,
+        HTTP_HEADER_X_REAL_IP,
+        &info.client_ip.to_canonical().to_string(),
+    );
+}
+
+/**
+ * Headers of the connection with the client or the backend, never forwarded
+ * Host can't be dropped through Connection
+ */
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed = get_header_list(headers, CONNECTION.as_str())
+        .iter()
+        .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+        .filter(|name| name != HOST)
+        .collect::<Vec<_>>();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HTTP_HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/**
+ * The proxy is appended to the Via chain, with the protocol version received
+ */
+pub fn add_via_header(headers: &mut HeaderMap, version: Version) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    let mut via = get_header_list(headers, VIA.as_str());
+    via.push(format!("{} {}", protocol, VIA_PSEUDONYM));
+    set_header(headers, VIA.as_str(), &via.join(", "));
+}
+
+/**
+ * Comma separated values of all the header lines, quotes are not handled
+ */
+fn get_header_list(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+fn set_header(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+/**
+ * for= parameter of a Forwarded element (RFC 7239)
+ */
+fn get_forwarded_for(element: &str) -> Option<String> {
+    element
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+/**
+ * 192.0.2.1, 192.0.2.1:1234, 2001:db8::1, [2001:db8::1] or [2001:db8::1]:1234
+ */
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+fn format_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    }
+}
+
+/**
+ * Quoted string when the value is not a token
+ */
+fn quote_forwarded_value(value: &str) -> String {
+    if value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+    {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    fn info(peer_ip: &str, trusted: bool) -> ForwardedInfo<'static> {
+        ForwardedInfo {
+            peer_ip: peer_ip.parse().unwrap(),
+            client_ip: peer_ip.parse().unwrap(),
+            trusted,
+            proto: "https",
+            host: "www.domain.com:8443",
+            port: 8443,
+        }
+    }
+
+    #[test]
+    fn client_ip_from_trusted_chain() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HTTP_HEADER_X_FORWARDED_FOR,
+            "1.1.1.1, 203.0.113.7, 10.0.0.2".parse().unwrap(),
+        );
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(
+            get_client_ip(peer, &headers, &trusted()),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        // untrusted peer: the chain is ignored
+        let peer: IpAddr = "198.51.100.1".parse().unwrap();
+        assert_eq!(get_client_ip(peer, &headers, &trusted()), peer);
+        // Forwarded when there is no X-Forwarded-For
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            FORWARDED,
+            "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.3"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            get_client_ip("10.0.0.1".parse().unwrap(), &headers, &trusted()),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        // unreadable hop: last readable one
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HTTP_HEADER_X_FORWARDED_FOR,
+            "1.1.1.1, unknown, 10.0.0.2".parse().unwrap(),
+        );
+        assert_eq!(
+            get_client_ip("10.0.0.1".parse().unwrap(), &headers, &trusted()),
+            "10.0.0.2".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn untrusted_headers_are_replaced() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HTTP_HEADER_X_FORWARDED_FOR, "1.1.1.1".parse().unwrap());
+        headers.insert(HTTP_HEADER_X_FORWARDED_PROTO, "http".parse().unwrap());
+        headers.insert(FORWARDED, "for=1.1.1.1".parse().unwrap());
+        set_forwarded_headers(&mut headers, &info("198.51.100.1", false));
+        assert_eq!(
+            headers.get(HTTP_HEADER_X_FORWARDED_FOR).unwrap(),
+            "198.51.100.1"
+        );
+        assert_eq!(headers.get(HTTP_HEADER_X_FORWARDED_PROTO).unwrap(), "https");
+        assert_eq!(
+            headers.get(HTTP_HEADER_X_FORWARDED_HOST).unwrap(),
+            "www.domain.com:8443"
+        );
+        assert_eq!(headers.get(HTTP_HEADER_X_FORWARDED_PORT).unwrap(), "8443");
+        assert_eq!(
+            headers.get(FORWARDED).unwrap(),
+            "for=198.51.100.1;host=\"www.domain.com:8443\";proto=https"
+        );
+        assert_eq!(headers.get(HTTP_HEADER_X_REAL_IP).unwrap(), "198.51.100.1");
+    }
+
+    #[test]
+    fn trusted_headers_are_appended() {
+        let mut headers = HeaderMap::new();
+        headers.append(HTTP_HEADER_X_FORWARDED_FOR, "1.1.1.1".parse().unwrap());
+        headers.append(HTTP_HEADER_X_FORWARDED_FOR, "10.0.0.2".parse().unwrap());
+        headers.insert(HTTP_HEADER_X_FORWARDED_PROTO, "http".parse().unwrap());
+        headers.insert(FORWARDED, "for=1.1.1.1".parse().unwrap());
+        let mut info = info("::ffff:10.0.0.1", true);
+        info.client_ip = "1.1.1.1".parse().unwrap();
+        set_forwarded_headers(&mut headers, &info);
+        assert_eq!(
+            headers.get(HTTP_HEADER_X_FORWARDED_FOR).unwrap(),
+            "1.1.1.1, 10.0.0.2, 10.0.0.1"
+        );
+        assert_eq!(headers.get(HTTP_HEADER_X_FORWARDED_PROTO).unwrap(), "http");
+        assert_eq!(
+            headers.get(FORWARDED).unwrap(),
+            "for=1.1.1.1, for=10.0.0.1;host=\"www.domain.com:8443\";proto=https"
+        );
+        assert_eq!(headers.get(HTTP_HEADER_X_REAL_IP).unwrap(), "1.1.1.1");
+    }
+}
@@ -1,12 +1,16 @@
 use arc_swap::ArcSwap;
-use hyper::{Request, server::conn::http1, service::service_fn};
+use hyper::{Request, body::Incoming, server::conn::http1, service::service_fn};
 
 use hyper_util::rt::{TokioIo, TokioTimer};
 use std::{net::SocketAddr, sync::Arc};
 use tokio::net::TcpListener;
 
 use crate::{
-    forwarders::{forwarder_handler::handle_request, forwarder_helper::get_http_client},
+    forwarders::{
+        forwarder_handler::handle_request,
+        forwarder_helper::get_http_client,
+        proxy_protocol::{ProxyProtocolClients, read_proxy_header},
+    },
     structs::{GenericError, ProxyConfig},
 };
 
@@ -28,32 +32,58 @@ pub async fn proxy_from_http(
 
     loop {
         match listener.accept().await {
-            Ok((tcp, peer_addr)) => {
+            Ok((mut tcp, peer_addr)) => {
                 let frontend_name = frontend_name.clone();
-                let svc = {
-                    // Clone the values we need to move into the closure
-                    let client = client.clone();
-                    let servers_tracker = servers_tracker.clone();
-                    let config = config.clone();
-                    let frontend_name = frontend_name.clone();
-                    // Create the service_fn
-                    service_fn(move |mut req: Request<hyper::body::Incoming>| {
-                        // Insert extensions
-                        req.extensions_mut().insert(frontend_name.clone());
-                        req.extensions_mut().insert(config.clone());
-                        req.extensions_mut().insert(peer_addr);
-                        req.extensions_mut().insert(client.clone());
-                        req.extensions_mut().insert(servers_tracker.clone());
-
-                        // Call the handler - no async/await here!
-                        handle_request(req)
-                    })
-                };
-                let io = TokioIo::new(tcp);
+                let accept_proxy_protocol = config
+                    .load()
+                    .frontends
+                    .iter()
+                    .any(|f| f.name == frontend_name && f.accept_proxy_protocol);
+                // Clone the values we need to move into the task
+                let client = client.clone();
+                let servers_tracker = servers_tracker.clone();
+                let config = config.clone();
 
                 tokio::task::spawn(async move {
-                    let svc = svc.clone();
-                    let frontend_name = frontend_name.clone();
+                    // Client address given by the load balancer
+                    let peer_addr = if accept_proxy_protocol {
+                        match read_proxy_header(&mut tcp).await {
+                            Ok(source) => source.unwrap_or(peer_addr),
+                            Err(e) => {
+                                eprintln!(
+                                    "[http listener error]: name: {} - from: {} - error: {}",
+                                    frontend_name, peer_addr, e
+                                );
+                                return;
+                            }
+                        }
+                    } else {
+                        peer_addr
+                    };
+                    // Clients of the PROXY protocol backends, for this connection
+                    let proxy_clients = Arc::new(ProxyProtocolClients::<Incoming>::default());
+                    let svc = {
+                        // Clone the values we need to move into the closure
+                        let client = client.clone();
+                        let servers_tracker = servers_tracker.clone();
+                        let config = config.clone();
+                        let frontend_name = frontend_name.clone();
+                        // Create the service_fn
+                        service_fn(move |mut req: Request<hyper::body::Incoming>| {
+                            // Insert extensions
+                            req.extensions_mut().insert(frontend_name.clone());
+                            req.extensions_mut().insert(config.clone());
+                            req.extensions_mut().insert(peer_addr);
+                            req.extensions_mut().insert(client.clone());
+                            req.extensions_mut().insert(proxy_clients.clone());
+                            req.extensions_mut().insert(servers_tracker.clone());
+
+                            // Call the handler - no async/await here!
+                            handle_request(req)
+                        })
+                    };
+                    let io = TokioIo::new(tcp);
+
                     if let Err(err) = http1::Builder::new()
                         .timer(TokioTimer::new())
                         .preserve_header_case(true)
@@ -1,31 +1,39 @@
-use arc_swap::ArcSwapAny;
-use hyper::{Request, server::conn::http1, service::service_fn};
+use arc_swap::{ArcSwap, ArcSwapAny};
+use hyper::{Request, body::Incoming, server::conn::http1, service::service_fn};
 
 use hyper_util::rt::{TokioIo, TokioTimer};
-use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{net::TcpListener, time::Instant};
 use tokio_rustls::TlsAcceptor;
 
 use crate::{
     forwarders::{
+        cert_resolver::Certificates,
         forwarder_handler::handle_request,
-        forwarder_helper::{create_tls_config, get_http_client, load_combined_pems},
+        forwarder_helper::{create_tls_config, get_http_client},
+        forwarder_tcp::forward_connection,
+        proxy_protocol::{ProxyProtocolClients, read_proxy_header},
+        sni_passthrough::{PrefixedStream, read_client_hello},
     },
-    structs::{GenericError, ProxyConfig},
+    structs::{GenericError, ProxyConfig, TlsServerName},
 };
 
 use super::servers_tracker::ServerTracker;
 
 pub async fn proxy_from_https(
     config: Arc<ArcSwapAny<Arc<ProxyConfig>>>,
-    certs_path: PathBuf,
+    certificates: Arc<ArcSwap<Certificates>>,
     servers_tracker: Arc<arc_swap::ArcSwapAny<Arc<ServerTracker>>>,
     frontend_name: String,
     addr: SocketAddr,
 ) -> Result<(), GenericError> {
-    // Load all certificates from directory
-    let cert_map = load_combined_pems(certs_path)?;
-    let tls_config = create_tls_config(cert_map)?;
+    let default_certificate = config
+        .load()
+        .frontends
+        .iter()
+        .find(|f| f.name == frontend_name)
+        .and_then(|f| f.default_certificate.clone());
+    let tls_config = create_tls_config(certificates, default_certificate.as_deref())?;
     let client = get_http_client();
 
     // Listener
@@ -50,67 +58,133 @@ pub async fn proxy_from_https(
             .map_err(|_| GenericError::from("Connection limit reached"))?;
 
         match listener.accept().await {
-            Ok((tcp, peer_addr)) => {
+            Ok((mut tcp, peer_addr)) => {
                 // println!("_peer_addr: {:?}", peer_addr);
                 let _permit = permit;
-                let svc = {
-                    // Clone the values we need to move into the closure
-                    let client = client.clone();
-                    let servers_tracker = servers_tracker.clone();
-                    let config = config.clone();
-                    let frontend_name = frontend_name.clone();
-                    // Create the service_fn
-                    service_fn(move |mut req: Request<hyper::body::Incoming>| {
-                        // Insert extensions
-                        req.extensions_mut().insert(frontend_name.clone());
-                        req.extensions_mut().insert(config.clone());
-                        req.extensions_mut().insert(peer_addr);
-                        req.extensions_mut().insert(client.clone());
-                        req.extensions_mut().insert(servers_tracker.clone());
-
-                        // Call the handler - no async/await here!
-                        handle_request(req)
-                    })
-                };
                 let start = Instant::now();
+                let accept_proxy_protocol = config
+                    .load()
+                    .frontends
+                    .iter()
+                    .any(|f| f.name == frontend_name && f.accept_proxy_protocol);
+                let config = config.clone();
+                let servers_tracker = servers_tracker.clone();
+                let client = client.clone();
                 let tls_acceptor = tls_acceptor.clone();
-                // connection accepted - let's check tls and continue if ok
                 let frontend_name = frontend_name.clone();
-                match tls_acceptor.accept(tcp).await {
-                    Ok(tls_stream) => {
-                        //println!("TLS handshake succeeded in {:?}", start.elapsed());
-                        // Handle the connection
-                        let io = TokioIo::new(tls_stream);
-                        let svc = svc.clone();
-
-                        tokio::task::spawn(async move {
-                            if let Err(err) = http1::Builder::new()
-                                .timer(TokioTimer::new())
-                                .header_read_timeout(Some(Duration::from_secs(5)))
-                                .auto_date_header(false)
-                                .serve_connection(io, svc)
-                                .with_upgrades()
-                                .await
-                            {
+
+                // PROXY header, ClientHello and TLS handshake read in the connection task:
+                // a slow client never holds the accept loop
+                tokio::task::spawn(async move {
+                    // Client address given by the load balancer, before the TLS handshake
+                    let peer_addr = if accept_proxy_protocol {
+                        match read_proxy_header(&mut tcp).await {
+                            Ok(source) => source.unwrap_or(peer_addr),
+                            Err(e) => {
+                                eprintln!(
+                                    "[https listener error]: name: {} - from: {} - error: {}",
+                                    frontend_name, peer_addr, e
+                                );
+                                return;
+                            }
+                        }
+                    } else {
+                        peer_addr
+                    };
+                    // ClientHello read first when some hosts are passed through
+                    let tracker = servers_tracker.load_full();
+                    let (client_hello, server_name) = if tracker.has_passthrough {
+                        match read_client_hello(&mut tcp).await {
+                            Ok(client_hello) => client_hello,
+                            Err(e) => {
                                 eprintln!(
-                                    "[https listener error]: name: {} - from: {} - error: {:?}",
-                                    frontend_name, peer_addr, err
+                                    "[https listener error]: name: {} - from: {} - error: {}",
+                                    frontend_name, peer_addr, e
                                 );
+                                return;
                             }
-                        });
+                        }
+                    } else {
+                        (Vec::new(), None)
+                    };
+                    if let Some(server_name) =
+                        server_name.filter(|name| tracker.get_passthrough_route(name).is_some())
+                    {
+                        // TLS terminated by the backend server
+                        let route = tracker.get_passthrough_route(&server_name).unwrap();
+                        if let Err(e) =
+                            forward_connection(tcp, peer_addr, &route.pool, Some(&client_hello))
+                                .await
+                        {
+                            eprintln!(
+                                "[https listener error]: name: {} - from: {} - passthrough: {} - error: {}",
+                                frontend_name, peer_addr, server_name, e
+                            );
+                        }
+                        return;
                     }
-                    Err(e) => {
+                    let tcp = PrefixedStream::new(client_hello, tcp);
+                    // connection accepted - let's check tls and continue if ok
+                    let tls_stream = match tls_acceptor.accept(tcp).await {
+                        Ok(tls_stream) => tls_stream,
+                        Err(e) => {
+                            eprintln!(
+                                "TLS failed after {:?}: {} - peer: {}",
+                                start.elapsed(),
+                                e,
+                                peer_addr
+                            );
+                            if let Some(inner) = e.get_ref() {
+                                eprintln!("Root cause: {:?}", inner.source());
+                            }
+                            return;
+                        }
+                    };
+                    //println!("TLS handshake succeeded in {:?}", start.elapsed());
+                    // Server name requested by the client
+                    let sni = tls_stream
+                        .get_ref()
+                        .1
+                        .server_name()
+                        .map(|name| TlsServerName(name.to_string()));
+                    // Clients of the PROXY protocol backends, for this connection
+                    let proxy_clients = Arc::new(ProxyProtocolClients::<Incoming>::default());
+                    let svc = {
+                        // Clone the values we need to move into the closure
+                        let frontend_name = frontend_name.clone();
+                        // Create the service_fn
+                        service_fn(move |mut req: Request<hyper::body::Incoming>| {
+                            // Insert extensions
+                            req.extensions_mut().insert(frontend_name.clone());
+                            req.extensions_mut().insert(config.clone());
+                            req.extensions_mut().insert(peer_addr);
+                            req.extensions_mut().insert(client.clone());
+                            req.extensions_mut().insert(proxy_clients.clone());
+                            req.extensions_mut().insert(servers_tracker.clone());
+                            if let Some(sni) = &sni {
+                                req.extensions_mut().insert(sni.clone());
+                            }
+
+                            // Call the handler - no async/await here!
+                            handle_request(req)
+                        })
+                    };
+                    // Handle the connection
+                    let io = TokioIo::new(tls_stream);
+                    if let Err(err) = http1::Builder::new()
+                        .timer(TokioTimer::new())
+                        .header_read_timeout(Some(Duration::from_secs(5)))
+                        .auto_date_header(false)
+                        .serve_connection(io, svc)
+                        .with_upgrades()
+                        .await
+                    {
                         eprintln!(
-                            "TLS failed after {:?}: {} - peer: {}",
-                            start.elapsed(),
-                            e,
-                            peer_addr
+                            "[https listener error]: name: {} - from: {} - error: {:?}",
+                            frontend_name, peer_addr, err
                         );
-                        if let Some(inner) = e.get_ref() {
-                            eprintln!("Root cause: {:?}", inner.source());
-                        }
                     }
-                }
+                });
             }
             Err(e) => {
                 if e.kind() != std::io::ErrorKind::WouldBlock {
@@ -120,3 +194,138 @@ pub async fn proxy_from_https(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forwarders::servers_tracker::ServersStates;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, crypto::aws_lc_rs};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    /**
+     * ClientHello of a rustls client, as sent on the wire
+     */
+    fn client_hello(server_name: &'static str) -> Vec<u8> {
+        let config = ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let mut connection =
+            ClientConnection::new(Arc::new(config), server_name.try_into().unwrap()).unwrap();
+        let mut client_hello = Vec::new();
+        connection.write_tls(&mut client_hello).unwrap();
+        client_hello
+    }
+
+    /**
+     * Passthrough server: answers "passthrough:" then echoes everything received
+     */
+    async fn start_stub_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    stream.write_all(b"passthrough:").await.unwrap();
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        port
+    }
+
+    async fn start_proxy(accept_proxy_protocol: bool) -> u16 {
+        let server_port = start_stub_server().await;
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let yaml = format!(
+            r#"
+frontends:
+  - name: "frontend-https"
+    protocol: "http"
+    addr: "127.0.0.1"
+    port: {port}
+    tls: true
+    active: true
+    accept_proxy_protocol: {accept_proxy_protocol}
+    acls:
+      - {{ name: "pass", host: "pass.domain.com", backend: "stub", passthrough: true }}
+pool_backends:
+  - name: "stub"
+    servers: ["stub"]
+pool_servers:
+  - {{ name: "stub", host: "127.0.0.1", port: {server_port}, protocol: "http", tls: false, active: true }}
+"#
+        );
+        let config: ProxyConfig = serde_yaml::from_str(&yaml).unwrap();
+        let config = Arc::new(ArcSwap::new(Arc::new(config)));
+        let mut tracker = ServerTracker::new();
+        tracker.populate(
+            "frontend-https".to_string(),
+            config.clone(),
+            &ServersStates::default(),
+        );
+        tokio::spawn(proxy_from_https(
+            config,
+            Arc::new(ArcSwap::from_pointee(Certificates::default())),
+            Arc::new(ArcSwap::new(Arc::new(tracker))),
+            "frontend-https".to_string(),
+            SocketAddr::from(([127, 0, 0, 1], port)),
+        ));
+        port
+    }
+
+    async fn connect(port: u16) -> TcpStream {
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    /**
+     * Passed through while a silent client is connected (no PROXY header, no ClientHello):
+     * answered well before the read timeouts
+     */
+    async fn passthrough_next_to_silent_client(accept_proxy_protocol: bool) {
+        let port = start_proxy(accept_proxy_protocol).await;
+        let _silent = connect(port).await;
+        let mut stream = connect(port).await;
+        if accept_proxy_protocol {
+            stream
+                .write_all(b"PROXY TCP4 203.0.113.7 127.0.0.1 56324 443\r\n")
+                .await
+                .unwrap();
+        }
+        stream
+            .write_all(&client_hello("pass.domain.com"))
+            .await
+            .unwrap();
+        let mut received = [0u8; 12];
+        tokio::time::timeout(Duration::from_secs(2), stream.read_exact(&mut received))
+            .await
+            .expect("blocked by the silent client")
+            .unwrap();
+        assert_eq!(&received, b"passthrough:");
+    }
+
+    #[tokio::test]
+    async fn silent_proxy_protocol_client_does_not_block_the_listener() {
+        passthrough_next_to_silent_client(true).await;
+    }
+
+    #[tokio::test]
+    async fn stalled_client_hello_does_not_block_the_listener() {
+        passthrough_next_to_silent_client(false).await;
+    }
+}
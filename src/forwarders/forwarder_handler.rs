@@ -1,82 +1,163 @@
-use arc_swap::ArcSwap;
-use hyper::{
-    HeaderMap, Request, Response, Uri,
-    body::{self, Incoming},
-    header::HeaderValue,
-};
+use hyper::{Request, Response, Uri, body::Incoming, header::HeaderValue};
 
+use arc_swap::ArcSwap;
+use cookie::Cookie;
 use hyper_tls::HttpsConnector;
 use hyper_util::client::legacy::{Client, connect::HttpConnector};
 use std::{net::SocketAddr, sync::Arc};
 
 use crate::{
     constants::{
-        HTTP_HEADER_X_FORWARDED_FOR, HTTP_HEADER_X_REAL_IP, HTTP_INTERNAL_SERVER,
-        INTERNAL_ROUTE_ANTIBOT, INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE,
+        HTTP_HEADER_REFRESH, INTERNAL_ROUTE_ACME_CHALLENGE, INTERNAL_ROUTE_ANTIBOT,
+        INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE, INTERNAL_ROUTE_ERROR_UNKNOWN_HOST,
     },
     forwarders::{
-        forwarder_helper::{get_upstream_uri, is_domain_configured_for_antibot},
+        forwarder_helper::{
+            get_acme_challenge_token, get_internal_redirect_route, get_redirect, get_upstream_uri,
+        },
         forwarder_ws::handle_websocket_upgrade,
     },
-    structs::ProxyConfig,
+    structs::{HeaderRule, LocationRewrite, ProxyConfig, TlsServerName},
 };
 
 use super::{
+    acl_matcher::normalize_host,
+    forwarded_headers::{
+        ForwardedInfo, add_via_header, get_client_ip, is_trusted_proxy, remove_hop_by_hop_headers,
+        set_forwarded_headers,
+    },
     forwarder_helper::{is_cookie_antibot, is_websocket_request},
-    servers_tracker::ServerTracker,
+    header_rules::{HeaderVariables, apply_header_rules},
+    proxy_protocol::{ProxyProtocolClients, get_proxy_source},
+    servers_tracker::{BalanceContext, ServerTracker},
+    tracked_body::TrackedBody,
 };
 
+/**
+ * Rewriting of the backend urls returned to the client
+ */
+pub struct UrlRewrite<'a> {
+    pub mode: LocationRewrite,
+    // scheme of the frontend
+    pub scheme: &'a str,
+    // Host header of the request, port included
+    pub original_host: &'a str,
+    // (host, port) of the servers of the backend
+    pub backend_servers: &'a [(String, u16)],
+}
+
+impl UrlRewrite<'_> {
+    /**
+     * Absolute url with the scheme and host of the frontend, None when unchanged
+     */
+    fn rewrite_url(&self, url: &str) -> Option<String> {
+        if self.mode == LocationRewrite::Off || self.original_host.is_empty() {
+            return None;
+        }
+        let uri = url.trim().parse::<Uri>().ok()?;
+        // Relative url: nothing to do
+        let scheme = uri.scheme_str()?;
+        let authority = uri.authority()?;
+        if self.mode == LocationRewrite::Backend {
+            let host = authority
+                .host()
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            let port = authority.port_u16().unwrap_or(match scheme {
+                "https" | "wss" => 443,
+                _ => 80,
+            });
+            if !self
+                .backend_servers
+                .iter()
+                .any(|(server_host, server_port)| {
+                    server_host.eq_ignore_ascii_case(host) && *server_port == port
+                })
+            {
+                return None;
+            }
+        }
+        let path_and_query = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+        Some(format!(
+            "{}://{}{}",
+            self.scheme, self.original_host, path_and_query
+        ))
+    }
+
+    /**
+     * Refresh: "5; url=http://..."
+     */
+    fn rewrite_refresh(&self, refresh: &str) -> Option<String> {
+        let position = refresh.to_ascii_lowercase().find("url=")? + "url=".len();
+        let (delay, url) = refresh.split_at(position);
+        let quote = if url.starts_with(['"', '\'']) {
+            &url[..1]
+        } else {
+            ""
+        };
+        let url = url.trim_matches(['"', '\'']);
+        self.rewrite_url(url)
+            .map(|url| format!("{}{}{}{}", delay, quote, url, quote))
+    }
+}
+
 /**
  * Alter output header client->listener (Response)
  */
-pub async fn set_response_header(original_host: String, response: &mut Response<Incoming>) {
+pub async fn set_response_header(
+    url_rewrite: &UrlRewrite<'_>,
+    sticky_cookie: Option<Cookie<'static>>,
+    header_rules: &[HeaderRule],
+    header_variables: &HeaderVariables,
+    response: &mut Response<Incoming>,
+) {
     //println!("Backend response: {:?}", response);
-    // Handle redirect responses (301, 302, etc.)
-    if response.status().is_redirection() {
-        //println!("Redirection detected: {:?}", response);
-        if let Some(location) = response.headers().get(hyper::header::LOCATION) {
-            //println!("location detected: {:?}", location);
-            if let Ok(location_str) = location.to_str() {
-                if let Ok(location_uri) = location_str.parse::<Uri>() {
-                    //println!("location_uri: {:?}", location_uri);
-                    // Check if the URI is absolute by looking for scheme
-                    if location_uri.scheme_str().is_some() {
-                        //println!("scheme_str ok");
-                        if original_host != "" {
-                            // Get path and query
-                            let path_and_query = location_uri
-                                .path_and_query()
-                                .map(|pq| pq.as_str())
-                                .unwrap_or("/");
-
-                            //println!("path_and_query: {:?}", path_and_query);
-                            // Rebuild URI with original proxy host/scheme
-                            let new_uri = format!("https://{}{}", original_host, path_and_query);
-                            //println!("new_uri: {:?}", new_uri);
-                            if let Ok(new_uri) = new_uri.parse::<Uri>() {
-                                response.headers_mut().insert(
-                                    hyper::header::LOCATION,
-                                    new_uri.to_string().parse().unwrap(),
-                                );
-                            }
-                        }
-                    }
-                }
-            }
+    // Headers of the backend connection
+    remove_hop_by_hop_headers(response.headers_mut());
+    let version = response.version();
+    add_via_header(response.headers_mut(), version);
+    // Sticky session: server chosen for the next requests
+    if let Some(sticky_cookie) = sticky_cookie
+        && let Ok(value) = HeaderValue::from_str(&sticky_cookie.to_string())
+    {
+        response
+            .headers_mut()
+            .append(hyper::header::SET_COOKIE, value);
+    }
+    // Backend urls (redirects, created resources...)
+    for name in [hyper::header::LOCATION, hyper::header::CONTENT_LOCATION] {
+        let new_url = response
+            .headers()
+            .get(&name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|url| url_rewrite.rewrite_url(url));
+        //println!("{}: {:?}", name, new_url);
+        if let Some(new_url) = new_url
+            && let Ok(value) = HeaderValue::from_str(&new_url)
+        {
+            response.headers_mut().insert(name, value);
         }
     }
+    let new_refresh = response
+        .headers()
+        .get(HTTP_HEADER_REFRESH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|refresh| url_rewrite.rewrite_refresh(refresh));
+    if let Some(new_refresh) = new_refresh
+        && let Ok(value) = HeaderValue::from_str(&new_refresh)
+    {
+        response.headers_mut().insert(HTTP_HEADER_REFRESH, value);
+    }
+    apply_header_rules(response.headers_mut(), header_rules, header_variables);
 }
 
 pub async fn handle_request(
     req: Request<hyper::body::Incoming>,
-) -> Result<Response<body::Incoming>, hyper_util::client::legacy::Error> {
+) -> Result<Response<TrackedBody>, hyper_util::client::legacy::Error> {
     // peer address:port
     let peer_addr = req.extensions().get::<SocketAddr>().cloned().unwrap();
     // println!(peer_addr: "{:?}", peer_addr);
 
-    let frontend_name = req.extensions().get::<String>().cloned().unwrap();
-    // println!("frontend_name: {:?}", frontend_name);
-
     let servers_tracker = req
         .extensions()
         .get::<Arc<arc_swap::ArcSwapAny<Arc<ServerTracker>>>>()
@@ -85,14 +166,6 @@ pub async fn handle_request(
         .clone();
     //println!("servers_tracker: {:?}", servers_tracker);
 
-    let config = req
-        .extensions()
-        .get::<Arc<ArcSwap<ProxyConfig>>>()
-        .cloned()
-        .unwrap()
-        .clone();
-    //println!("config: {:?}", config);
-
     if is_websocket_request(&req) {
         println!("websocket request detected");
         return handle_websocket_upgrade(req, servers_tracker).await;
@@ -104,6 +177,24 @@ pub async fn handle_request(
         .cloned()
         .unwrap()
         .clone();
+    let proxy_clients = req
+        .extensions()
+        .get::<Arc<ProxyProtocolClients<Incoming>>>()
+        .cloned()
+        .unwrap();
+
+    let frontend_name = req.extensions().get::<String>().cloned().unwrap();
+    let config = req
+        .extensions()
+        .get::<Arc<ArcSwap<ProxyConfig>>>()
+        .cloned()
+        .unwrap();
+    // https frontend only
+    let sni = req
+        .extensions()
+        .get::<TlsServerName>()
+        .map(|sni| sni.0.clone())
+        .unwrap_or_default();
 
     let (parts, body) = req.into_parts();
 
@@ -112,38 +203,104 @@ pub async fn handle_request(
         .headers
         .get("host")
         .and_then(|h| h.to_str().ok())
+        .or_else(|| parts.uri.authority().map(|a| a.as_str()))
         .map(|s| s.to_string())
-        .unwrap(); // Convert to &str safely
+        // no host: unknown host
+        .unwrap_or_default();
     //println!("original_host: {}", original_host);
 
-    // Prepare antibot
-    let is_antibot_protected = is_domain_configured_for_antibot(
-        frontend_name.clone(),
-        original_host.clone(),
-        config.clone(),
-    );
+    // Real client, behind the trusted proxies
+    let trusted_proxies = servers_tracker.load().trusted_proxies.clone();
+    let client_ip = get_client_ip(peer_addr.ip(), &parts.headers, &trusted_proxies);
 
     // upstream uri
-    let mut upstream_uri = get_upstream_uri(original_host.clone(), servers_tracker.clone(), false);
-    if upstream_uri == "" {
+    let balance_context = BalanceContext {
+        client_ip,
+        method: &parts.method,
+        headers: &parts.headers,
+        uri: &parts.uri,
+    };
+    let upstream = get_upstream_uri(
+        original_host.clone(),
+        servers_tracker.clone(),
+        false,
+        &balance_context,
+    );
+    // Antibot for this acl ?
+    let is_antibot_protected = upstream
+        .acl
+        .as_ref()
+        .is_some_and(|acl| acl.antibot.unwrap_or(false));
+    // Redirect answered by the proxy ?
+    let redirect = get_redirect(
+        &servers_tracker.load(),
+        upstream.acl.as_ref(),
+        &original_host,
+        &parts.uri,
+    );
+    // Header rules of the backend and acl
+    let header_variables = HeaderVariables::new(
+        client_ip.to_canonical().to_string(),
+        normalize_host(&original_host),
+        frontend_name,
+        sni,
+        &upstream.header_rules,
+    );
+    // ACME HTTP-01 challenge of a certificate order in progress
+    let acme_challenge = get_acme_challenge_token(
+        &config.load(),
+        servers_tracker.load().frontend_tls,
+        &parts.uri,
+    );
+    let mut upstream_uri = upstream.uri;
+    let mut tracked_server = upstream.tracked_server;
+    let internal_server = servers_tracker.load().internal_server;
+    if let Some(token) = &acme_challenge {
+        // Internal server - ACME challenge
+        upstream_uri = format!(
+            "http://{}/{}/{}",
+            internal_server, INTERNAL_ROUTE_ACME_CHALLENGE, token
+        );
+        // Backend server not involved
+        tracked_server = None;
+    } else if let Some((status, location)) = &redirect {
+        // Internal server - Redirect
+        upstream_uri = format!(
+            "http://{}/{}",
+            internal_server,
+            get_internal_redirect_route(*status, location)
+        );
+        // Backend server not involved
+        tracked_server = None;
+    } else if upstream.acl.is_none() {
+        // Internal server - No acl and no default backend for this host
+        upstream_uri = format!(
+            "http://{}/{}/{}{}",
+            internal_server,
+            INTERNAL_ROUTE_ERROR_UNKNOWN_HOST,
+            servers_tracker.load().unknown_host_status,
+            parts
+                .uri
+                .path_and_query()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/")
+        );
+    } else if upstream_uri.is_empty() {
         // Internal server - No server available
         upstream_uri = format!(
-            "http://127.0.0.1:{}/{}{}",
-            HTTP_INTERNAL_SERVER,
-            INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE,
-            parts.uri.to_string()
+            "http://{}/{}{}",
+            internal_server, INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE, parts.uri
         );
     } else {
         // antibot for this host ?
-        if is_antibot_protected {
-            if !is_cookie_antibot(parts.headers.get("cookie")) {
-                upstream_uri = format!(
-                    "http://127.0.0.1:{}/{}",
-                    HTTP_INTERNAL_SERVER, INTERNAL_ROUTE_ANTIBOT,
-                );
-            }
+        if is_antibot_protected && !is_cookie_antibot(parts.headers.get("cookie")) {
+            upstream_uri = format!(
+                "http://{}/{}{}",
+                internal_server, INTERNAL_ROUTE_ANTIBOT, parts.uri
+            );
+            // Backend server not involved
+            tracked_server = None;
         }
-        upstream_uri = format!("{}{}", upstream_uri, parts.uri.to_string());
     }
     let upstream_uri = upstream_uri.parse::<Uri>().unwrap();
     //====> To check round robin load balance
@@ -151,47 +308,549 @@ pub async fn handle_request(
 
     // Build forwarded request with all original headers
     let forwarded_req = {
-        let mut builder = Request::builder().method(parts.method).uri(upstream_uri);
-
-        // Copy all headers from original request
-        for (name, value) in parts.headers.iter() {
-            builder = builder.header(name, value);
-        }
+        let mut builder = Request::builder()
+            .method(parts.method.clone())
+            .uri(upstream_uri);
 
-        // Add X-forwarded-for headers
-        let peer_ip_as_string = peer_addr.ip().to_string();
-        let peer_as_str = peer_ip_as_string.as_str();
-        let mut headers_map = HeaderMap::new();
-        headers_map.append(
-            HTTP_HEADER_X_FORWARDED_FOR,
-            HeaderValue::from_str(peer_as_str).unwrap(),
+        // Copy all headers from original request except the hop-by-hop ones,
+        // with the forwarding headers, altered by the header rules
+        let mut headers = parts.headers.clone();
+        remove_hop_by_hop_headers(&mut headers);
+        add_via_header(&mut headers, parts.version);
+        set_forwarded_headers(
+            &mut headers,
+            &ForwardedInfo {
+                peer_ip: peer_addr.ip(),
+                client_ip,
+                trusted: is_trusted_proxy(peer_addr.ip(), &trusted_proxies),
+                proto: if servers_tracker.load().frontend_tls {
+                    "https"
+                } else {
+                    "http"
+                },
+                host: &original_host,
+                port: servers_tracker.load().frontend_port,
+            },
         );
-        headers_map.append(
-            HTTP_HEADER_X_REAL_IP,
-            HeaderValue::from_str(peer_as_str).unwrap(),
+        apply_header_rules(
+            &mut headers,
+            &upstream.header_rules.request,
+            &header_variables,
         );
-        let _ = builder.headers_mut().insert(&mut headers_map);
+        for (name, value) in headers.iter() {
+            builder = builder.header(name, value);
+        }
 
         // Body
         builder.body(body).unwrap()
     };
 
+    // Request in progress on the backend server, until the response body is complete
+    let request_guard = tracked_server.as_ref().map(|t| t.track_request());
+
     // println!("Forwarding traffic for {}", name);
-    let response = client.request(forwarded_req).await;
+    let send_proxy_protocol = tracked_server
+        .as_ref()
+        .and_then(|t| t.server.send_proxy_protocol.clone());
+    let response = match send_proxy_protocol {
+        Some(version) => {
+            proxy_clients
+                .get(&version, Some(get_proxy_source(peer_addr, client_ip)))
+                .request(forwarded_req)
+                .await
+        }
+        None => client.request(forwarded_req).await,
+    };
 
     match response {
         Ok(mut response) => {
-            let original_host = original_host.clone();
-            set_response_header(original_host, &mut response).await;
+            if let Some(tracked_server) = &tracked_server {
+                if response.status().is_server_error() {
+                    tracked_server.report_failure();
+                } else {
+                    tracked_server.report_success();
+                }
+            }
+            let url_rewrite = UrlRewrite {
+                // Redirect built by the proxy: location already final
+                mode: match (&redirect, &upstream.acl) {
+                    (None, Some(acl)) => acl.rewrite_location.clone(),
+                    _ => LocationRewrite::Off,
+                },
+                scheme: if servers_tracker.load().frontend_tls {
+                    "https"
+                } else {
+                    "http"
+                },
+                original_host: &original_host,
+                backend_servers: &upstream.backend_servers,
+            };
+            let sticky_cookie = tracked_server
+                .as_ref()
+                .and_then(|t| t.get_sticky_cookie(&parts.headers));
+            set_response_header(
+                &url_rewrite,
+                sticky_cookie,
+                &upstream.header_rules.response,
+                &header_variables,
+                &mut response,
+            )
+            .await;
             //println!("Response before sending to http server: {:?}", response);
-            Ok::<Response<body::Incoming>, hyper_util::client::legacy::Error>(response)
+            Ok::<Response<TrackedBody>, hyper_util::client::legacy::Error>(
+                response.map(|body| TrackedBody::new(body, request_guard)),
+            )
         }
         Err(e) => {
-            eprintln!("Request forwarding error: {:?}", e,);
+            eprintln!(
+                "Request forwarding error: client: {} - {:?}",
+                client_ip.to_canonical(),
+                e
+            );
+            if let Some(tracked_server) = tracked_server {
+                tracked_server.report_failure();
+            }
             // @todo
-            // set backend disabled
             // return html content
             Err(e)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        acme_client::AcmeChallenges,
+        forwarders::{
+            forwarder_from_http::proxy_from_http, internal_http::internal_http,
+            servers_tracker::ServersStates,
+        },
+        structs::ProxyConfig,
+    };
+    use arc_swap::ArcSwap;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    fn url_rewrite<'a>(
+        mode: LocationRewrite,
+        backend_servers: &'a [(String, u16)],
+    ) -> UrlRewrite<'a> {
+        UrlRewrite {
+            mode,
+            scheme: "http",
+            original_host: "www.domain.com:3000",
+            backend_servers,
+        }
+    }
+
+    #[test]
+    fn only_backend_urls_are_rewritten() {
+        let servers = [("172.0.0.1".to_string(), 31222), ("app".to_string(), 80)];
+        let rewrite = url_rewrite(LocationRewrite::Backend, &servers);
+        assert_eq!(
+            rewrite.rewrite_url("http://172.0.0.1:31222/login?next=/"),
+            Some("http://www.domain.com:3000/login?next=/".to_string())
+        );
+        assert_eq!(
+            rewrite.rewrite_url("http://APP/home"),
+            Some("http://www.domain.com:3000/home".to_string())
+        );
+        // other port, external domain, relative url
+        assert_eq!(rewrite.rewrite_url("http://172.0.0.1:8080/"), None);
+        assert_eq!(rewrite.rewrite_url("https://accounts.example.com/"), None);
+        assert_eq!(rewrite.rewrite_url("/login"), None);
+    }
+
+    #[test]
+    fn rewrite_modes() {
+        let servers = [("app".to_string(), 80)];
+        let always = url_rewrite(LocationRewrite::Always, &servers);
+        assert_eq!(
+            always.rewrite_url("https://accounts.example.com/a"),
+            Some("http://www.domain.com:3000/a".to_string())
+        );
+        let off = url_rewrite(LocationRewrite::Off, &servers);
+        assert_eq!(off.rewrite_url("http://app/a"), None);
+    }
+
+    #[test]
+    fn refresh_url_is_rewritten() {
+        let servers = [("app".to_string(), 80)];
+        let rewrite = url_rewrite(LocationRewrite::Backend, &servers);
+        assert_eq!(
+            rewrite.rewrite_refresh("5; URL='http://app/next'"),
+            Some("5; URL='http://www.domain.com:3000/next'".to_string())
+        );
+        assert_eq!(rewrite.rewrite_refresh("5"), None);
+        assert_eq!(rewrite.rewrite_refresh("0;url=https://example.com/"), None);
+    }
+
+    /**
+     * Head of an http/1.1 message, and the body announced by Content-Length
+     */
+    async fn read_message(stream: &mut TcpStream) -> (String, String) {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        let head_end = loop {
+            if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed before the end of the head");
+            data.extend_from_slice(&buf[..n]);
+        };
+        let head = String::from_utf8_lossy(&data[..head_end]).to_lowercase();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map(|length| length.trim().parse::<usize>().unwrap())
+            .unwrap_or(0);
+        while data.len() < head_end + length {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed before the end of the body");
+            data.extend_from_slice(&buf[..n]);
+        }
+        let body = String::from_utf8_lossy(&data[head_end..head_end + length]).to_lowercase();
+        (head, body)
+    }
+
+    /**
+     * Stub backend: answers with hop-by-hop headers, the request head received as body
+     */
+    async fn start_stub_backend() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (head, _) = read_message(&mut stream).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\n\
+                         Content-Length: {}\r\n\
+                         Connection: keep-alive, X-Backend-Hop\r\n\
+                         Keep-Alive: timeout=5\r\n\
+                         X-Backend-Hop: 1\r\n\
+                         Proxy-Authenticate: Basic\r\n\
+                         Trailer: X-Checksum\r\n\
+                         Via: 1.1 backend-cache\r\n\
+                         X-Backend: stub\r\n\r\n{}",
+                        head.len(),
+                        head
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        port
+    }
+
+    /**
+     * http frontend forwarding every request to the stub backend,
+     * with the PROXY protocol (v1) on both sides when proxy_protocol is set
+     */
+    async fn start_proxy(backend_port: u16, proxy_protocol: bool) -> u16 {
+        let port = get_free_port();
+        let yaml = format!(
+            r#"
+frontends:
+  - name: "frontend-http"
+    protocol: "http"
+    addr: "127.0.0.1"
+    port: {port}
+    tls: false
+    active: true
+    acls: []
+    default_backend: "stub"
+    accept_proxy_protocol: {proxy_protocol}
+pool_backends:
+  - name: "stub"
+    servers: ["stub"]
+pool_servers:
+  - name: "stub"
+    host: "127.0.0.1"
+    port: {backend_port}
+    protocol: "http"
+    tls: false
+    active: true
+{send_proxy_protocol}
+"#,
+            send_proxy_protocol = if proxy_protocol {
+                "    send_proxy_protocol: \"v1\""
+            } else {
+                ""
+            }
+        );
+        spawn_frontend(&yaml, port);
+        port
+    }
+
+    fn get_free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /**
+     * Listener of the frontend-http frontend of the yaml configuration,
+     * with its own internal server
+     */
+    fn spawn_frontend(yaml: &str, port: u16) {
+        let config: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        let config = Arc::new(ArcSwap::new(Arc::new(config)));
+        let mut tracker = ServerTracker::new();
+        tracker.populate(
+            "frontend-http".to_string(),
+            config.clone(),
+            &ServersStates::default(),
+        );
+        tracker.internal_server = SocketAddr::from(([127, 0, 0, 1], get_free_port()));
+        tokio::spawn(internal_http(
+            "internal".to_string(),
+            tracker.internal_server,
+            AcmeChallenges::default(),
+        ));
+        tokio::spawn(proxy_from_http(
+            config,
+            Arc::new(ArcSwap::new(Arc::new(tracker))),
+            "frontend-http".to_string(),
+            SocketAddr::from(([127, 0, 0, 1], port)),
+        ));
+    }
+
+    async fn send_request(proxy_port: u16, request: &str) -> (String, String) {
+        let mut stream = loop {
+            match TcpStream::connect(("127.0.0.1", proxy_port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        stream.write_all(request.as_bytes()).await.unwrap();
+        read_message(&mut stream).await
+    }
+
+    async fn send_through_proxy(request: &str, proxy_protocol: bool) -> (String, String) {
+        let proxy_port = start_proxy(start_stub_backend().await, proxy_protocol).await;
+        send_request(proxy_port, request).await
+    }
+
+    const REQUEST: &str = "GET /hop HTTP/1.1\r\n\
+                           Host: www.domain.com\r\n\
+                           Connection: keep-alive, X-Client-Hop\r\n\
+                           Keep-Alive: 300\r\n\
+                           X-Client-Hop: 1\r\n\
+                           Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\
+                           Proxy-Connection: keep-alive\r\n\
+                           TE: trailers\r\n\
+                           Trailer: X-Checksum\r\n\
+                           Via: 1.0 edge\r\n\
+                           X-Client: curl\r\n\r\n";
+
+    #[tokio::test]
+    async fn request_hop_by_hop_headers_are_not_forwarded() {
+        // the backend request head is the response body
+        let (_, backend_head) = send_through_proxy(REQUEST, false).await;
+        for name in [
+            "connection:",
+            "keep-alive:",
+            "x-client-hop:",
+            "proxy-authorization:",
+            "proxy-connection:",
+            "te:",
+            "trailer:",
+            "transfer-encoding:",
+        ] {
+            assert!(
+                !backend_head.lines().any(|line| line.starts_with(name)),
+                "{} forwarded to the backend:\n{}",
+                name,
+                backend_head
+            );
+        }
+        assert!(backend_head.contains("\r\nhost: www.domain.com\r\n"));
+        assert!(backend_head.contains("\r\nx-client: curl\r\n"));
+        assert!(backend_head.contains("\r\nvia: 1.0 edge, 1.1 http_reverse_proxy\r\n"));
+    }
+
+    #[tokio::test]
+    async fn response_hop_by_hop_headers_are_not_returned() {
+        let (head, _) = send_through_proxy(REQUEST, false).await;
+        for name in [
+            "keep-alive:",
+            "x-backend-hop:",
+            "proxy-authenticate:",
+            "trailer:",
+        ] {
+            assert!(
+                !head.lines().any(|line| line.starts_with(name)),
+                "{} returned to the client:\n{}",
+                name,
+                head
+            );
+        }
+        assert!(head.starts_with("http/1.1 200 ok\r\n"));
+        assert!(head.contains("\r\nx-backend: stub\r\n"));
+        assert!(head.contains("\r\nvia: 1.1 backend-cache, 1.1 http_reverse_proxy\r\n"));
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_client_address_is_passed_to_the_backend() {
+        let request = format!("PROXY TCP4 203.0.113.7 127.0.0.1 56324 80\r\n{}", REQUEST);
+        let (head, backend_head) = send_through_proxy(&request, true).await;
+        assert!(head.starts_with("http/1.1 200 ok\r\n"));
+        // PROXY line sent by the proxy, then the request
+        let (proxy_line, backend_head) = backend_head.split_once("\r\n").unwrap();
+        assert!(
+            proxy_line.starts_with("proxy tcp4 203.0.113.7 127.0.0.1 56324 "),
+            "{}",
+            proxy_line
+        );
+        assert!(backend_head.starts_with("get /hop http/1.1\r\n"));
+        assert!(backend_head.contains("\r\nx-forwarded-for: 203.0.113.7\r\n"));
+    }
+
+    /**
+     * Head of the websocket handshake received by the backend, through the proxy
+     */
+    async fn send_websocket_handshake(request: &str, proxy_protocol: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (head, _) = read_message(&mut stream).await;
+            sender.send(head).unwrap();
+        });
+        let proxy_port = start_proxy(backend_port, proxy_protocol).await;
+        let (head, _) = send_request(proxy_port, request).await;
+        assert!(head.starts_with("http/1.1 101 "), "{}", head);
+        receiver.await.unwrap()
+    }
+
+    const WS_REQUEST: &str = "GET /chat HTTP/1.1\r\n\
+                              Host: www.domain.com\r\n\
+                              Connection: Upgrade\r\n\
+                              Upgrade: websocket\r\n\
+                              Sec-WebSocket-Version: 13\r\n\
+                              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+    #[tokio::test]
+    async fn proxy_protocol_header_is_sent_to_websocket_backends() {
+        let request = format!(
+            "PROXY TCP4 203.0.113.7 127.0.0.1 56324 80\r\n{}",
+            WS_REQUEST
+        );
+        let backend_head = send_websocket_handshake(&request, true).await;
+        let (proxy_line, backend_head) = backend_head.split_once("\r\n").unwrap();
+        assert!(
+            proxy_line.starts_with("proxy tcp4 203.0.113.7 127.0.0.1 56324 "),
+            "{}",
+            proxy_line
+        );
+        assert!(backend_head.starts_with("get /chat http/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn websocket_messages_are_relayed() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::{accept_async, client_async, tungstenite::Message};
+        // Echo backend
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = ws.next().await {
+                if message.is_text() {
+                    ws.send(message).await.unwrap();
+                }
+            }
+        });
+        let proxy_port = start_proxy(backend_port, false).await;
+        let stream = loop {
+            match TcpStream::connect(("127.0.0.1", proxy_port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let (mut ws, _) = client_async("ws://www.domain.com/chat", stream)
+            .await
+            .unwrap();
+        ws.send(Message::text("hello")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("hello"));
+    }
+
+    #[tokio::test]
+    async fn websocket_handshake_carries_the_client_headers() {
+        let request = WS_REQUEST.replace(
+            "Connection: Upgrade\r\n",
+            "Connection: Upgrade, X-Client-Hop\r\n\
+             X-Client-Hop: 1\r\n\
+             X-Client: 1\r\n\
+             X-Forwarded-For: 192.0.2.99\r\n\
+             Sec-WebSocket-Extensions: permessage-deflate\r\n",
+        );
+        let backend_head = send_websocket_handshake(&request, false).await;
+        assert!(backend_head.starts_with("get /chat http/1.1\r\n"));
+        for expected in [
+            "host: www.domain.com",
+            "x-client: 1",
+            "x-forwarded-for: 127.0.0.1",
+            "x-forwarded-host: www.domain.com",
+            "via: 1.1 ",
+            "connection: upgrade",
+            "upgrade: websocket",
+            "sec-websocket-version: 13",
+        ] {
+            assert!(
+                backend_head.contains(&format!("\r\n{}", expected)),
+                "{}: {}",
+                expected,
+                backend_head
+            );
+        }
+        for unexpected in ["x-client-hop", "192.0.2.99", "sec-websocket-extensions"] {
+            assert!(!backend_head.contains(unexpected), "{}", backend_head);
+        }
+    }
+
+    #[tokio::test]
+    async fn client_redirect_location_is_ignored() {
+        let port = get_free_port();
+        let yaml = format!(
+            r#"
+frontends:
+  - name: "frontend-http"
+    protocol: "http"
+    addr: "127.0.0.1"
+    port: {port}
+    tls: false
+    active: true
+    acls:
+      - name: "old"
+        host: "old.domain.com"
+        redirect: {{ to: "https://www.domain.com/", status: 301 }}
+pool_backends: []
+pool_servers: []
+"#
+        );
+        spawn_frontend(&yaml, port);
+        // Header of the former internal redirects
+        let request = "GET /_internal_server/redirect/302/aHR0cHM6Ly9ldmlsLmV4YW1wbGUuY29tLw HTTP/1.1\r\n\
+                       Host: old.domain.com\r\n\
+                       X-Internal-Redirect-Location: https://evil.example.com/\r\n\r\n";
+        let (head, _) = send_request(port, request).await;
+        assert!(head.starts_with("http/1.1 301 "), "{}", head);
+        assert!(
+            head.contains("\r\nlocation: https://www.domain.com/\r\n"),
+            "{}",
+            head
+        );
+        assert!(!head.contains("evil"), "{}", head);
+    }
+}
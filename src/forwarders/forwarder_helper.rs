@@ -1,23 +1,29 @@
-use std::{collections::HashMap, error::Error, fs, path::PathBuf, sync::Arc, time::Duration};
+use std::{sync::Arc, time::Duration};
 
 use arc_swap::{ArcSwap, ArcSwapAny};
-use hyper::{Request, body, header::HeaderValue};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hyper::{HeaderMap, Request, Uri, body, header::HeaderValue};
 use hyper_tls::HttpsConnector;
 use hyper_util::{
     client::legacy::{Client, connect::HttpConnector},
     rt::TokioExecutor,
 };
-use rustls::{ServerConfig, crypto::aws_lc_rs::sign::any_supported_type, sign::CertifiedKey};
-
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
 use uuid::Uuid;
 
 use crate::{
-    constants::{ANTIBOT_COOKIE_NAME, POOL_IDLE_TIMEOUT, POOL_MAX_IDLE_PER_HOST},
-    structs::{BackendServer, GenericError, GenericResult, ProxyConfig},
+    constants::{
+        ACME_CHALLENGE_PATH, ANTIBOT_COOKIE_NAME, HTTPS_REDIRECT_STATUS, INTERNAL_ROUTE_REDIRECT,
+        POOL_IDLE_TIMEOUT, POOL_MAX_IDLE_PER_HOST,
+    },
+    structs::{AclConfig, BackendServer, GenericResult, HeaderRules, ProxyConfig},
 };
 
-use super::servers_tracker::ServerTracker;
+use super::{
+    acl_matcher::normalize_host,
+    cert_resolver::{CertResolver, Certificates},
+    servers_tracker::{BalanceContext, ServerTracker, TrackedServer},
+};
 use cookie::Cookie;
 
 pub fn build_upstream_uri(backend_server: BackendServer, is_web_socket: bool) -> String {
@@ -37,26 +43,29 @@ pub fn build_upstream_uri(backend_server: BackendServer, is_web_socket: bool) ->
     //port
     upstream = format!("{}:{}", upstream, backend_server.port);
     // Optional path
-    if backend_server.path.is_some() {
-        upstream = format!("{}:{}", upstream, backend_server.path.clone().unwrap());
+    if let Some(path) = backend_server.path {
+        upstream = format!("{}{}", upstream, path.trim_end_matches('/'));
     }
-    return upstream;
+    upstream
 }
 
-// Creates a TLS configuration from loaded certificates
+// Creates a TLS configuration from the loaded certificates
+// default_certificate: name of the certificate for clients without SNI or with an unknown name
 pub fn create_tls_config(
-    cert_map: HashMap<String, (Vec<CertificateDer<'static>>, PrivateKeyDer<'_>)>,
+    certificates: Arc<ArcSwap<Certificates>>,
+    default_certificate: Option<&str>,
 ) -> GenericResult<Arc<ServerConfig>> {
-    let mut cert_resolver = rustls::server::ResolvesServerCertUsingSni::new();
-
-    for (domain, (cert_chain, private_key)) in cert_map {
-        let key = any_supported_type(&private_key)
-            .map_err(|e| format!("Unsupported private key: {}", e))?;
-        let cert_key = CertifiedKey::new(cert_chain, key);
-        cert_resolver
-            .add(&domain, cert_key)
-            .map_err(|e| format!("Failed to add certificate for {}: {}", domain, e))?;
-        println!("Tls domain loaded: {}", domain);
+    let mut cert_resolver = CertResolver::new(certificates.clone());
+    if let Some(default_certificate) = default_certificate {
+        // Never fatal: served once loaded (certs reload, ACME)
+        if certificates.load().get(default_certificate).is_none() {
+            eprintln!(
+                "Warning: Tls default certificate {} not loaded, SNI only until it is",
+                default_certificate
+            );
+        }
+        cert_resolver.set_default(default_certificate);
+        println!("Tls default certificate: {}", default_certificate);
     }
 
     // Support both TLS 1.2 and 1.3 for better compatibility
@@ -76,72 +85,6 @@ pub fn create_tls_config(
     Ok(Arc::new(config))
 }
 
-// Load certificates from combined PEM files (cert + key in one file)
-pub fn load_combined_pems(
-    cert_dir: PathBuf,
-) -> Result<
-    HashMap<std::string::String, (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
-    GenericError,
-> {
-    let mut cert_map = HashMap::new();
-
-    println!("Configuration certs path: {:?}", cert_dir);
-    let certs_files_list = fs::read_dir(cert_dir).map_err(|e| -> GenericError { Box::new(e) })?;
-    for entry in certs_files_list {
-        let entry = entry.map_err(|e| -> GenericError { Box::new(e) })?;
-        let path = entry.path();
-        if path.extension().map_or(false, |ext| ext == "pem") {
-            let domain = path
-                .file_stem()
-                .and_then(|s| s.to_str())
-                .ok_or_else(|| {
-                    Box::<dyn Error + Send + Sync + 'static>::from("Invalid PEM filename")
-                })?
-                .to_string();
-
-            let file_contents = fs::read(&path).map_err(|e| -> GenericError { Box::new(e) })?;
-            let mut reader = std::io::Cursor::new(file_contents);
-
-            // Read all items from the PEM file, collecting any errors
-            let items: Vec<rustls_pemfile::Item> = rustls_pemfile::read_all(&mut reader)
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|e| -> GenericError { Box::new(e) })?;
-
-            // Process items into certificates and private key
-            let mut cert_chain = Vec::new();
-            let mut private_key = None;
-
-            for item in items {
-                match item {
-                    rustls_pemfile::Item::X509Certificate(cert) => {
-                        cert_chain.push(rustls::pki_types::CertificateDer::from(cert.to_vec()));
-                    }
-                    rustls_pemfile::Item::Pkcs8Key(key) if private_key.is_none() => {
-                        private_key = Some(rustls::pki_types::PrivateKeyDer::from(key));
-                    }
-                    _ => {}
-                }
-            }
-
-            if cert_chain.is_empty() {
-                eprintln!("Warning: No certificates found in {}", path.display());
-                continue;
-            }
-
-            let private_key = match private_key {
-                Some(key) => key,
-                None => {
-                    eprintln!("Warning: No private key found in {}", path.display());
-                    continue;
-                }
-            };
-
-            cert_map.insert(domain, (cert_chain, private_key));
-        }
-    }
-    Ok(cert_map)
-}
-
 /**
  * return an http connector
  */
@@ -161,88 +104,183 @@ pub fn get_http_client() -> Client<hyper_tls::HttpsConnector<HttpConnector>, bod
 }
 
 /**
- * return the final uri selecting the backend with roundrobin
+ * Acl matching the request and the uri of the selected backend server
+ */
+pub struct Upstream {
+    pub acl: Option<AclConfig>,
+    // "" when no server is available
+    pub uri: String,
+    pub tracked_server: Option<TrackedServer>,
+    // (host, port) of the servers of the backend
+    pub backend_servers: Vec<(String, u16)>,
+    pub header_rules: HeaderRules,
+}
+
+/**
+ * return the final uri (path and query included) selecting the backend with the balance mode,
+ * and the selected server
  */
 pub fn get_upstream_uri(
     original_host: String,
     servers_tracker: Arc<ArcSwapAny<Arc<ServerTracker>>>,
     is_web_socket: bool,
-) -> String {
+    context: &BalanceContext,
+) -> Upstream {
+    let servers_tracker = servers_tracker.load_full();
+    // Which acl ?
+    let Some(route) = servers_tracker.get_route(&original_host, context) else {
+        return Upstream {
+            acl: None,
+            uri: "".to_string(),
+            tracked_server: None,
+            backend_servers: Vec::new(),
+            header_rules: HeaderRules::default(),
+        };
+    };
     // Which backend ?
-    let backend_server = servers_tracker
-        .load()
-        .as_ref()
-        .get_next_backend(&original_host);
+    let backend_server = route.pool.get_next_server(context);
     //println!("backend_server: {:?}", backend_server);
-    if backend_server.is_some() {
-        build_upstream_uri(backend_server.unwrap(), is_web_socket)
-    } else {
-        "".to_string()
+    let uri = match &backend_server {
+        Some(backend_server) => format!(
+            "{}{}",
+            build_upstream_uri(backend_server.server.clone(), is_web_socket),
+            route.get_path_and_query(context.uri)
+        ),
+        None => "".to_string(),
+    };
+    Upstream {
+        acl: Some(route.acl.clone()),
+        uri,
+        tracked_server: backend_server,
+        backend_servers: route
+            .pool
+            .servers
+            .iter()
+            .map(|tracked| (tracked.server.host.clone(), tracked.server.port))
+            .collect(),
+        header_rules: route.header_rules.clone(),
     }
 }
 
 /**
- * Browser config to look for frontend/host is set with antibot
+ * Token of an ACME HTTP-01 challenge, answered on the http frontends when acme is set
  */
-pub fn is_domain_configured_for_antibot(
-    frontend_name: String,
-    original_host: String,
-    config: Arc<ArcSwap<ProxyConfig>>,
-) -> bool {
-    let config = config.clone().load();
-    let lookup_table = config
-        // filter frontend on frontend_name
-        .frontends
-        .iter()
-        .find(|f| f.name == frontend_name)
-        // Get acls
-        .into_iter()
-        .flat_map(|frontend| &frontend.acls)
-        .find(|a| a.host == original_host)
-        .into_iter()
-        .collect::<Vec<_>>();
-
-    if lookup_table.len() == 1 {
-        let antibot_state = lookup_table.get(0).unwrap().antibot;
-        if antibot_state.is_some() && antibot_state.unwrap() {
-            //println!("Configured with antibot");
-            return true;
-        }
+pub fn get_acme_challenge_token(
+    config: &ProxyConfig,
+    frontend_tls: bool,
+    uri: &Uri,
+) -> Option<String> {
+    if frontend_tls || config.acme.is_none() {
+        return None;
     }
-    // println!("Not configured with antibot");
-    return false;
+    let token = uri.path().strip_prefix(ACME_CHALLENGE_PATH)?;
+    (!token.is_empty()
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+    .then(|| token.to_string())
+}
+
+/**
+ * Redirect answered by the proxy: (http status, location)
+ * https_redirect of the frontend first, then the redirect of the acl
+ */
+pub fn get_redirect(
+    servers_tracker: &ServerTracker,
+    acl: Option<&AclConfig>,
+    original_host: &str,
+    uri: &Uri,
+) -> Option<(u16, String)> {
+    let path_and_query = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let host = normalize_host(original_host);
+    if let Some(port) = servers_tracker.https_redirect_port {
+        let host = if host.contains(':') {
+            // ipv6
+            format!("[{}]", host)
+        } else {
+            host
+        };
+        let port = if port == 443 {
+            "".to_string()
+        } else {
+            format!(":{}", port)
+        };
+        return Some((
+            HTTPS_REDIRECT_STATUS,
+            format!("https://{}{}{}", host, port, path_and_query),
+        ));
+    }
+    let redirect = acl?.redirect.as_ref()?;
+    let location = redirect
+        .to
+        .replace("{host}", &host)
+        .replace("{path}", uri.path())
+        .replace("{query}", uri.query().unwrap_or(""));
+    if redirect.preserve_path {
+        Some((
+            redirect.status,
+            format!("{}{}", location.trim_end_matches('/'), path_and_query),
+        ))
+    } else {
+        Some((redirect.status, location))
+    }
+}
+
+/**
+ * Internal route answering a redirect, the location is part of the route built by the proxy
+ */
+pub fn get_internal_redirect_route(status: u16, location: &str) -> String {
+    format!(
+        "{}/{}/{}",
+        INTERNAL_ROUTE_REDIRECT,
+        status,
+        URL_SAFE_NO_PAD.encode(location)
+    )
 }
 
 /**
  * Simple cookie...
  */
 pub fn get_cookie_antibot(host: String) -> Cookie<'static> {
-    let cookie = Cookie::build((ANTIBOT_COOKIE_NAME, Uuid::new_v4().to_string()))
+    Cookie::build((ANTIBOT_COOKIE_NAME, Uuid::new_v4().to_string()))
         .domain(host)
         .path("/")
         .secure(false)
         .http_only(true)
         .same_site(cookie::SameSite::Strict)
         .max_age(cookie::time::Duration::hours(2))
-        .build();
-    return cookie;
+        .build()
 }
 
 pub fn is_cookie_antibot(cookie_http_header: Option<&HeaderValue>) -> bool {
-    if cookie_http_header.is_none() {
-        return false;
-    } else {
-        // check antibot cookie
-        for cookie in Cookie::split_parse(cookie_http_header.unwrap().to_str().unwrap()) {
-            let cookie = cookie;
-            if cookie.is_ok() && cookie.unwrap().name() == ANTIBOT_COOKIE_NAME {
-                return true;
+    match cookie_http_header {
+        None => false,
+        Some(cookie_http_header) => {
+            // check antibot cookie
+            for cookie in Cookie::split_parse(cookie_http_header.to_str().unwrap()).flatten() {
+                if cookie.name() == ANTIBOT_COOKIE_NAME {
+                    return true;
+                }
             }
+            false
         }
-        return false;
     }
 }
 
+/**
+ * Value of the cookie "name" from the Cookie headers
+ */
+pub fn get_cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .flatten()
+        .find(|c| c.name() == name)
+        .map(|c| c.value().to_string())
+}
+
 // Helper function to check WebSocket request
 pub fn is_websocket_request(req: &Request<hyper::body::Incoming>) -> bool {
     req.headers()
@@ -0,0 +1,314 @@
+use arc_swap::ArcSwap;
+use hyper::{HeaderMap, Method, Uri};
+use hyper_tls::HttpsConnector;
+use hyper_util::rt::TokioIo;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, copy_bidirectional},
+    net::TcpListener,
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+use tower_service::Service;
+
+use crate::{
+    constants::TCP_BACKEND_CONNECT_TIMEOUT,
+    forwarders::{
+        cert_resolver::Certificates,
+        forwarder_helper::create_tls_config,
+        proxy_protocol::{ProxyHeaderConnector, read_proxy_header},
+    },
+    structs::{GenericError, ProxyConfig},
+};
+
+use super::servers_tracker::{BackendPool, BalanceContext, ServerTracker};
+
+/**
+ * Layer 4 frontend: each connection (TLS terminated when tls is set)
+ * is spliced to a server of the default backend
+ */
+pub async fn proxy_from_tcp(
+    config: Arc<ArcSwap<ProxyConfig>>,
+    certificates: Option<Arc<ArcSwap<Certificates>>>,
+    servers_tracker: Arc<ArcSwap<ServerTracker>>,
+    frontend_name: String,
+    addr: SocketAddr,
+) -> Result<(), GenericError> {
+    let default_certificate = config
+        .load()
+        .frontends
+        .iter()
+        .find(|f| f.name == frontend_name)
+        .and_then(|f| f.default_certificate.clone());
+    let tls_acceptor = match certificates {
+        Some(certificates) => Some(TlsAcceptor::from(create_tls_config(
+            certificates,
+            default_certificate.as_deref(),
+        )?)),
+        None => None,
+    };
+
+    let listener = TcpListener::bind(addr).await?;
+    println!(
+        "TCP listener: {} is listening on: {}{}",
+        &frontend_name,
+        addr,
+        if tls_acceptor.is_some() { " (tls)" } else { "" }
+    );
+
+    loop {
+        match listener.accept().await {
+            Ok((mut tcp, peer_addr)) => {
+                let _ = tcp.set_nodelay(true);
+                let accept_proxy_protocol = config
+                    .load()
+                    .frontends
+                    .iter()
+                    .any(|f| f.name == frontend_name && f.accept_proxy_protocol);
+                let frontend_name = frontend_name.clone();
+                let servers_tracker = servers_tracker.clone();
+                let tls_acceptor = tls_acceptor.clone();
+
+                tokio::task::spawn(async move {
+                    // Client address given by the load balancer
+                    let peer_addr = if accept_proxy_protocol {
+                        match read_proxy_header(&mut tcp).await {
+                            Ok(source) => source.unwrap_or(peer_addr),
+                            Err(e) => {
+                                eprintln!(
+                                    "[tcp listener error]: name: {} - from: {} - error: {}",
+                                    frontend_name, peer_addr, e
+                                );
+                                return;
+                            }
+                        }
+                    } else {
+                        peer_addr
+                    };
+                    let servers_tracker = servers_tracker.load_full();
+                    let Some(route) = &servers_tracker.default_route else {
+                        eprintln!(
+                            "[tcp listener error]: name: {} - from: {} - error: no default backend",
+                            frontend_name, peer_addr
+                        );
+                        return;
+                    };
+                    let result = match tls_acceptor {
+                        Some(tls_acceptor) => match tls_acceptor.accept(tcp).await {
+                            Ok(tls_stream) => {
+                                forward_connection(tls_stream, peer_addr, &route.pool, None).await
+                            }
+                            Err(e) => Err(format!("TLS failed: {}", e).into()),
+                        },
+                        None => forward_connection(tcp, peer_addr, &route.pool, None).await,
+                    };
+                    if let Err(e) = result {
+                        eprintln!(
+                            "[tcp listener error]: name: {} - from: {} - error: {}",
+                            frontend_name, peer_addr, e
+                        );
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("[ACCEPT ERROR] {:?}", e);
+            }
+        }
+    }
+}
+
+/**
+ * Connect to the server chosen by the balancing of the pool,
+ * then copy both ways until one side closes
+ * passthrough: ClientHello already read, TLS is left to the server
+ */
+pub async fn forward_connection<S>(
+    mut client_stream: S,
+    peer_addr: SocketAddr,
+    pool: &BackendPool,
+    passthrough: Option<&[u8]>,
+) -> Result<(), GenericError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // No request: hash on the client ip only
+    let headers = HeaderMap::new();
+    let uri = Uri::from_static("/");
+    let context = BalanceContext {
+        client_ip: peer_addr.ip(),
+        method: &Method::GET,
+        headers: &headers,
+        uri: &uri,
+    };
+    let tracked_server = pool
+        .get_next_server(&context)
+        .ok_or("no backend server available")?;
+    let server = &tracked_server.server;
+
+    // https: TLS to the server, unless the client TLS is passed through
+    let host = if server.host.contains(':') {
+        format!("[{}]", server.host)
+    } else {
+        server.host.clone()
+    };
+    let uri = format!(
+        "{}://{}:{}",
+        if server.tls && passthrough.is_none() {
+            "https"
+        } else {
+            "http"
+        },
+        host,
+        server.port
+    )
+    .parse::<Uri>()?;
+    let mut connector = HttpsConnector::new_with_connector(ProxyHeaderConnector::new(
+        server.send_proxy_protocol.clone(),
+        Some(peer_addr),
+    ));
+    let connecting = timeout(
+        Duration::from_secs(TCP_BACKEND_CONNECT_TIMEOUT),
+        connector.call(uri),
+    );
+    let backend_stream = match connecting.await {
+        Ok(Ok(backend_stream)) => {
+            tracked_server.report_success();
+            backend_stream
+        }
+        Ok(Err(e)) => {
+            tracked_server.report_failure();
+            return Err(format!("server {}: {}", server.name, e).into());
+        }
+        Err(_) => {
+            tracked_server.report_failure();
+            return Err(format!("server {}: connect timeout", server.name).into());
+        }
+    };
+
+    // Connection in progress on the backend server, until one side closes
+    let _request_guard = tracked_server.track_request();
+    let mut backend_stream = TokioIo::new(backend_stream);
+    if let Some(client_hello) = passthrough {
+        backend_stream.write_all(client_hello).await?;
+    }
+    copy_bidirectional(&mut client_stream, &mut backend_stream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forwarders::servers_tracker::ServersStates;
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    /**
+     * Stub server: answers "{name}:" then echoes everything received
+     */
+    async fn start_stub_server(name: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    stream
+                        .write_all(format!("{}:", name).as_bytes())
+                        .await
+                        .unwrap();
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        port
+    }
+
+    async fn start_proxy(servers: &[(&str, u16)]) -> u16 {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let pool_servers = servers
+            .iter()
+            .map(|(name, port)| {
+                format!(
+                    "  - {{ name: \"{name}\", host: \"127.0.0.1\", port: {port}, \
+                     protocol: \"tcp\", tls: false, active: true }}\n"
+                )
+            })
+            .collect::<String>();
+        let names = servers
+            .iter()
+            .map(|(name, _)| format!("\"{}\"", name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let yaml = format!(
+            r#"
+frontends:
+  - name: "frontend-tcp"
+    protocol: "tcp"
+    addr: "127.0.0.1"
+    port: {port}
+    tls: false
+    active: true
+    default_backend: "stub"
+pool_backends:
+  - name: "stub"
+    servers: [{names}]
+pool_servers:
+{pool_servers}"#
+        );
+        let config: ProxyConfig = serde_yaml::from_str(&yaml).unwrap();
+        let config = Arc::new(ArcSwap::new(Arc::new(config)));
+        let mut tracker = ServerTracker::new();
+        tracker.populate(
+            "frontend-tcp".to_string(),
+            config.clone(),
+            &ServersStates::default(),
+        );
+        tokio::spawn(proxy_from_tcp(
+            config,
+            None,
+            Arc::new(ArcSwap::new(Arc::new(tracker))),
+            "frontend-tcp".to_string(),
+            SocketAddr::from(([127, 0, 0, 1], port)),
+        ));
+        port
+    }
+
+    async fn exchange(proxy_port: u16, message: &str) -> String {
+        let mut stream = loop {
+            match TcpStream::connect(("127.0.0.1", proxy_port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream.write_all(message.as_bytes()).await.unwrap();
+        let mut received = String::new();
+        let mut buf = [0u8; 64];
+        while !received.ends_with(message) {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed, received: {}", received);
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn connections_are_spliced_to_the_backend_servers() {
+        let s0 = start_stub_server("s0").await;
+        let s1 = start_stub_server("s1").await;
+        let proxy_port = start_proxy(&[("s0", s0), ("s1", s1)]).await;
+        // roundrobin, one server per connection
+        let mut received = vec![
+            exchange(proxy_port, "EHLO domain.com\r\n").await,
+            exchange(proxy_port, "EHLO domain.com\r\n").await,
+        ];
+        received.sort();
+        assert_eq!(
+            received,
+            vec!["s0:EHLO domain.com\r\n", "s1:EHLO domain.com\r\n"]
+        );
+    }
+}
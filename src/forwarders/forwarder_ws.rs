@@ -2,39 +2,119 @@ use arc_swap::ArcSwap;
 
 use futures::Sink;
 use futures_util::{SinkExt, stream::StreamExt};
-use hyper::{Method, Request, Response, Uri, body};
+use hyper::{
+    Method, Request, Response, Uri,
+    header::{
+        CONNECTION, HeaderValue, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY,
+        SEC_WEBSOCKET_VERSION, UPGRADE,
+    },
+};
 use hyper_util::rt::TokioIo;
-use std::sync::Arc;
-
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpStream;
 use tokio_tungstenite::{
-    connect_async,
-    tungstenite::{self, Message},
+    MaybeTlsStream, WebSocketStream, client_async_tls,
+    tungstenite::{self, Message, handshake::client::generate_key},
 };
+use tower_service::Service;
 
-use crate::constants::{HTTP_INTERNAL_SERVER, INTERNAL_ROUTE_MAKE_WEBSOCKET, SECRET_WS_GUID};
+use crate::{
+    constants::{
+        INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE, INTERNAL_ROUTE_ERROR_UNKNOWN_HOST,
+        INTERNAL_ROUTE_MAKE_WEBSOCKET, SECRET_WS_GUID,
+    },
+    structs::{GenericError, ProxyProtocolVersion},
+};
 
 use super::{
-    forwarder_helper::{get_http_client, get_upstream_uri},
-    servers_tracker::ServerTracker,
+    forwarded_headers::{
+        ForwardedInfo, add_via_header, get_client_ip, is_trusted_proxy, remove_hop_by_hop_headers,
+        set_forwarded_headers,
+    },
+    forwarder_helper::{
+        get_http_client, get_internal_redirect_route, get_redirect, get_upstream_uri,
+    },
+    proxy_protocol::{ProxyHeaderConnector, get_proxy_source},
+    servers_tracker::{BalanceContext, ServerTracker},
+    tracked_body::TrackedBody,
 };
 
 pub async fn handle_websocket_upgrade(
     mut req: Request<hyper::body::Incoming>,
     servers_tracker: Arc<ArcSwap<ServerTracker>>,
-) -> Result<Response<body::Incoming>, hyper_util::client::legacy::Error> {
+) -> Result<Response<TrackedBody>, hyper_util::client::legacy::Error> {
     let upgraded_fut = hyper::upgrade::on(&mut req);
+    // peer address:port
+    let peer_addr = req.extensions().get::<SocketAddr>().cloned().unwrap();
 
     let (parts, body) = req.into_parts();
     let original_host = parts
         .headers
         .get("host")
         .and_then(|h| h.to_str().ok())
+        .or_else(|| parts.uri.authority().map(|a| a.as_str()))
         .map(|s| s.to_string())
-        .unwrap(); // Convert to &str safely
+        // no host: unknown host
+        .unwrap_or_default();
 
-    let upstream_uri = get_upstream_uri(original_host.clone(), servers_tracker.clone(), true)
-        .parse::<Uri>()
-        .unwrap();
+    // Real client, behind the trusted proxies
+    let client_ip = get_client_ip(
+        peer_addr.ip(),
+        &parts.headers,
+        &servers_tracker.load().trusted_proxies,
+    );
+    let balance_context = BalanceContext {
+        client_ip,
+        method: &parts.method,
+        headers: &parts.headers,
+        uri: &parts.uri,
+    };
+    let upstream = get_upstream_uri(
+        original_host.clone(),
+        servers_tracker.clone(),
+        true,
+        &balance_context,
+    );
+    let internal_server = servers_tracker.load().internal_server;
+    let redirect = get_redirect(
+        &servers_tracker.load(),
+        upstream.acl.as_ref(),
+        &original_host,
+        &parts.uri,
+    );
+    if upstream.uri.is_empty() || redirect.is_some() {
+        // Redirect, no acl or no server available: internal response instead of the upgrade
+        let route = match (&redirect, &upstream.acl) {
+            (Some((status, location)), _) => get_internal_redirect_route(*status, location),
+            (None, None) => format!(
+                "{}/{}",
+                INTERNAL_ROUTE_ERROR_UNKNOWN_HOST,
+                servers_tracker.load().unknown_host_status
+            ),
+            (None, Some(_)) => INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE.to_string(),
+        };
+        let internal_upstream_uri = format!(
+            "http://{}/{}{}",
+            internal_server,
+            route,
+            parts
+                .uri
+                .path_and_query()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/")
+        );
+        let forwarded_req = Request::builder()
+            .method(Method::GET)
+            .uri(internal_upstream_uri)
+            .body(body)
+            .unwrap();
+        return get_http_client()
+            .request(forwarded_req)
+            .await
+            .map(|response| response.map(|body| TrackedBody::new(body, None)));
+    }
+    let tracked_server = upstream.tracked_server;
+    let upstream_uri = upstream.uri.parse::<Uri>().unwrap();
 
     println!("upstream_uri: {}", upstream_uri);
 
@@ -51,8 +131,8 @@ pub async fn handle_websocket_upgrade(
 
     // Create the upgrade response from internal (because of the Incoming type)
     let internal_upstream_uri = format!(
-        "http://127.0.0.1:{}/{}/{}",
-        HTTP_INTERNAL_SERVER, INTERNAL_ROUTE_MAKE_WEBSOCKET, accept
+        "http://{}/{}/{}",
+        internal_server, INTERNAL_ROUTE_MAKE_WEBSOCKET, accept
     );
     println!("Uri to get websocket header: {}", internal_upstream_uri);
     let forwarded_req = {
@@ -65,8 +145,51 @@ pub async fn handle_websocket_upgrade(
     let client = get_http_client();
     let response = client.request(forwarded_req).await;
 
+    // Handshake with the backend server: headers of the client with the forwarding ones,
+    // the key and the extensions belong to the connection of the proxy
+    let upstream_request = {
+        let tracker = servers_tracker.load();
+        let mut headers = parts.headers.clone();
+        remove_hop_by_hop_headers(&mut headers);
+        add_via_header(&mut headers, parts.version);
+        set_forwarded_headers(
+            &mut headers,
+            &ForwardedInfo {
+                peer_ip: peer_addr.ip(),
+                client_ip,
+                trusted: is_trusted_proxy(peer_addr.ip(), &tracker.trusted_proxies),
+                proto: if tracker.frontend_tls {
+                    "https"
+                } else {
+                    "http"
+                },
+                host: &original_host,
+                port: tracker.frontend_port,
+            },
+        );
+        headers.remove(SEC_WEBSOCKET_EXTENSIONS);
+        headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+        headers.insert(
+            SEC_WEBSOCKET_KEY,
+            HeaderValue::from_str(&generate_key()).unwrap(),
+        );
+        let mut request = Request::get(upstream_uri).body(()).unwrap();
+        *request.headers_mut() = headers;
+        request
+    };
+
+    // Tunnel in progress on the backend server, until one side closes
+    let request_guard = tracked_server.as_ref().map(|t| t.track_request());
+    let send_proxy_protocol = tracked_server
+        .as_ref()
+        .and_then(|t| t.server.send_proxy_protocol.clone());
+    let proxy_source = get_proxy_source(peer_addr, client_ip);
+
     // Spawn a task to handle the WebSocket connection
     tokio::spawn(async move {
+        let _request_guard = request_guard;
         let upgraded = upgraded_fut
             .await
             .expect("Error during WebSocket handshake");
@@ -80,10 +203,23 @@ pub async fn handle_websocket_upgrade(
         )
         .await;
 
-        // Connection to backend
-        let (ws_upstream, _) = connect_async(upstream_uri)
-            .await
-            .expect("Failed to connect to destination");
+        // Connection to backend, counted by the passive health check
+        let ws_upstream =
+            match connect_upstream(upstream_request, send_proxy_protocol, proxy_source).await {
+                Ok(ws_upstream) => {
+                    if let Some(tracked_server) = &tracked_server {
+                        tracked_server.report_success();
+                    }
+                    ws_upstream
+                }
+                Err(e) => {
+                    if let Some(tracked_server) = &tracked_server {
+                        tracked_server.report_failure();
+                    }
+                    eprintln!("[websocket error]: connection to the server failed: {}", e);
+                    return;
+                }
+            };
 
         // Linking streams
         let (ws_server_sender, ws_server_receiver) = ws_server.split();
@@ -103,7 +239,25 @@ pub async fn handle_websocket_upgrade(
         }
     });
 
-    Ok::<Response<body::Incoming>, hyper_util::client::legacy::Error>(response.unwrap())
+    Ok::<Response<TrackedBody>, hyper_util::client::legacy::Error>(
+        response.unwrap().map(|body| TrackedBody::new(body, None)),
+    )
+}
+
+/**
+ * Websocket connection to the backend server, the PROXY header (if any) written first
+ */
+async fn connect_upstream(
+    upstream_request: Request<()>,
+    send_proxy_protocol: Option<ProxyProtocolVersion>,
+    proxy_source: SocketAddr,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, GenericError> {
+    let stream = ProxyHeaderConnector::new(send_proxy_protocol, Some(proxy_source))
+        .call(upstream_request.uri().clone())
+        .await?
+        .into_inner();
+    let (ws_upstream, _) = client_async_tls(upstream_request, stream).await?;
+    Ok(ws_upstream)
 }
 
 /**
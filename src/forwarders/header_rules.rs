@@ -0,0 +1,196 @@
+use hyper::{
+    HeaderMap,
+    header::{HeaderName, HeaderValue},
+};
+use uuid::Uuid;
+
+use crate::structs::{HeaderAction, HeaderRule, HeaderRules};
+
+/**
+ * Values available to the header rules
+ */
+#[derive(Debug, Clone, Default)]
+pub struct HeaderVariables {
+    pub client_ip: String,
+    pub host: String,
+    pub frontend: String,
+    // empty without tls
+    pub sni: String,
+    pub request_id: String,
+}
+
+impl HeaderVariables {
+    /**
+     * request_id is generated only when a rule may need it
+     */
+    pub fn new(
+        client_ip: String,
+        host: String,
+        frontend: String,
+        sni: String,
+        rules: &HeaderRules,
+    ) -> Self {
+        let request_id = if rules.request.is_empty() && rules.response.is_empty() {
+            "".to_string()
+        } else {
+            Uuid::new_v4().to_string()
+        };
+        Self {
+            client_ip,
+            host,
+            frontend,
+            sni,
+            request_id,
+        }
+    }
+
+    pub fn interpolate(&self, value: &str) -> String {
+        value
+            .replace("{client_ip}", &self.client_ip)
+            .replace("{host}", &self.host)
+            .replace("{frontend}", &self.frontend)
+            .replace("{sni}", &self.sni)
+            .replace("{request_id}", &self.request_id)
+    }
+}
+
+/**
+ * Rules of the backend, then rules of the acl
+ */
+pub fn merge_header_rules(
+    backend_rules: Option<&HeaderRules>,
+    acl_rules: Option<&HeaderRules>,
+) -> HeaderRules {
+    let mut rules = HeaderRules::default();
+    for other in [backend_rules, acl_rules].into_iter().flatten() {
+        rules.request.extend(other.request.iter().cloned());
+        rules.response.extend(other.response.iter().cloned());
+    }
+    rules
+}
+
+/**
+ * Check names and values, the variables are not replaced
+ */
+pub fn validate_header_rule(rule: &HeaderRule) -> Result<(), String> {
+    HeaderName::from_bytes(rule.name.as_bytes())
+        .map_err(|e| format!("invalid header name '{}': {}", rule.name, e))?;
+    match (&rule.action, &rule.value) {
+        (HeaderAction::Remove, _) => Ok(()),
+        (HeaderAction::Rename, Some(value)) => HeaderName::from_bytes(value.as_bytes())
+            .map(|_| ())
+            .map_err(|e| format!("invalid header name '{}': {}", value, e)),
+        (_, Some(value)) => HeaderValue::from_str(value)
+            .map(|_| ())
+            .map_err(|e| format!("invalid header value '{}': {}", value, e)),
+        (_, None) => Err(format!("header {}: value is required", rule.name)),
+    }
+}
+
+/**
+ * Apply the rules in order, invalid rules are ignored (see validate_header_rule)
+ */
+pub fn apply_header_rules(
+    headers: &mut HeaderMap,
+    rules: &[HeaderRule],
+    variables: &HeaderVariables,
+) {
+    for rule in rules {
+        let Ok(name) = HeaderName::from_bytes(rule.name.as_bytes()) else {
+            continue;
+        };
+        let value = rule
+            .value
+            .as_ref()
+            .map(|value| variables.interpolate(value));
+        match (&rule.action, value) {
+            (HeaderAction::Remove, _) => {
+                headers.remove(&name);
+            }
+            (HeaderAction::Rename, Some(new_name)) => {
+                if let Ok(new_name) = HeaderName::from_bytes(new_name.as_bytes()) {
+                    let values = headers.get_all(&name).iter().cloned().collect::<Vec<_>>();
+                    headers.remove(&name);
+                    for value in values {
+                        headers.append(&new_name, value);
+                    }
+                }
+            }
+            (HeaderAction::Add, Some(value)) => {
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    headers.append(name, value);
+                }
+            }
+            (HeaderAction::Set, Some(value)) => {
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    headers.insert(name, value);
+                }
+            }
+            (_, None) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: HeaderAction, name: &str, value: Option<&str>) -> HeaderRule {
+        HeaderRule {
+            action,
+            name: name.to_string(),
+            value: value.map(|v| v.to_string()),
+        }
+    }
+
+    #[test]
+    fn rules_are_applied_in_order() {
+        let mut headers = HeaderMap::new();
+        headers.insert("server", "nginx".parse().unwrap());
+        headers.append("x-old", "1".parse().unwrap());
+        headers.append("x-old", "2".parse().unwrap());
+        headers.insert("x-trace", "a".parse().unwrap());
+        let variables = HeaderVariables {
+            client_ip: "10.0.0.1".to_string(),
+            host: "www.domain.com".to_string(),
+            frontend: "frontend-https".to_string(),
+            sni: "www.domain.com".to_string(),
+            request_id: "42".to_string(),
+        };
+        apply_header_rules(
+            &mut headers,
+            &[
+                rule(HeaderAction::Remove, "Server", None),
+                rule(HeaderAction::Rename, "x-old", Some("x-new")),
+                rule(HeaderAction::Add, "x-trace", Some("{frontend}")),
+                rule(HeaderAction::Set, "x-request-id", Some("{request_id}")),
+                rule(
+                    HeaderAction::Set,
+                    "x-client",
+                    Some("{client_ip} {host} {sni}"),
+                ),
+            ],
+            &variables,
+        );
+        assert!(headers.get("server").is_none());
+        assert!(headers.get("x-old").is_none());
+        assert_eq!(headers.get_all("x-new").iter().count(), 2);
+        assert_eq!(
+            headers.get_all("x-trace").iter().collect::<Vec<_>>(),
+            vec!["a", "frontend-https"]
+        );
+        assert_eq!(headers.get("x-request-id").unwrap(), "42");
+        assert_eq!(
+            headers.get("x-client").unwrap(),
+            "10.0.0.1 www.domain.com www.domain.com"
+        );
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(validate_header_rule(&rule(HeaderAction::Remove, "Server", None)).is_ok());
+        assert!(validate_header_rule(&rule(HeaderAction::Set, "bad name", Some("1"))).is_err());
+        assert!(validate_header_rule(&rule(HeaderAction::Set, "x-a", None)).is_err());
+        assert!(validate_header_rule(&rule(HeaderAction::Rename, "x-a", Some("b c"))).is_err());
+    }
+}
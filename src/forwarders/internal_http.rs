@@ -1,3 +1,4 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use bytes::Bytes;
 use http_body_util::Full;
 use hyper::{
@@ -10,18 +11,22 @@ use tokio::net::TcpListener;
 use tokio_tungstenite::tungstenite::http;
 
 use crate::{
+    acme_client::AcmeChallenges,
     constants::{
-        INTERNAL_ROUTE_ANTIBOT, INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE,
-        INTERNAL_ROUTE_MAKE_WEBSOCKET,
+        INTERNAL_ROUTE_ACME_CHALLENGE, INTERNAL_ROUTE_ANTIBOT,
+        INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE, INTERNAL_ROUTE_ERROR_UNKNOWN_HOST,
+        INTERNAL_ROUTE_MAKE_WEBSOCKET, INTERNAL_ROUTE_REDIRECT,
     },
     html::{template_html_antibot, template_html_internal_error},
     structs::GenericError,
 };
 
-use super::forwarder_helper::get_cookie_antibot;
+use super::{acl_matcher::normalize_host, forwarder_helper::get_cookie_antibot};
 
 enum InternalServerErrors {
     ServerUnavailable,
+    // 404 or 421
+    UnknownHost(StatusCode),
     RouteNotFound,
 }
 
@@ -32,28 +37,43 @@ async fn internal_error(
     let error_code: String;
     let p1: String;
     let p2: String;
+    let status: StatusCode;
+    // internal route to remove from the original uri
+    let route: String;
     match error {
         InternalServerErrors::RouteNotFound => {
             error_code = "Fallback Route not found".to_string();
             p1 = "???".to_string();
             p2 = "???".to_string();
+            status = StatusCode::SERVICE_UNAVAILABLE;
+            route = "".to_string();
+        }
+        InternalServerErrors::UnknownHost(unknown_host_status) => {
+            error_code = unknown_host_status.to_string();
+            p1 = "This site is not served here. Please check the address.".to_string();
+            p2 = "".to_string();
+            status = unknown_host_status;
+            route = format!(
+                "/{}/{}",
+                INTERNAL_ROUTE_ERROR_UNKNOWN_HOST,
+                unknown_host_status.as_u16()
+            );
         }
         InternalServerErrors::ServerUnavailable => {
             error_code = "503 Service Unavailable".to_string();
             p1 = "Our servers are temporarily unavailable due to high traffic or maintenance. Please try again later.".to_string();
             p2 = "If the problem persists, contact <a href='mailto:support@example.com'>support@example.com</a>.".to_string();
+            status = StatusCode::SERVICE_UNAVAILABLE;
+            route = format!("/{}", INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE);
         }
     }
-    let final_path = parts.uri.to_string().replace(
-        format!("/{}", INTERNAL_ROUTE_ERROR_NO_BACKEND_SERVER_AVAILABLE).as_str(),
-        "",
-    );
+    let final_path = parts.uri.to_string().replacen(route.as_str(), "", 1);
     let html = template_html_internal_error(error_code, p1, p2, final_path);
     let body = Full::new(Bytes::from(html));
     //println!("body: {:?}", body);
     let mut response = Response::new(body);
     // Change http code
-    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    *response.status_mut() = status;
     Ok(response)
 }
 
@@ -71,8 +91,8 @@ async fn antibot(parts: http::request::Parts) -> Result<Response<Full<Bytes>>, I
     *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
 
     let host = parts.headers.get("host");
-    if host.is_some() {
-        let cookie = get_cookie_antibot(host.unwrap().to_str().unwrap().to_string());
+    if let Some(host) = host {
+        let cookie = get_cookie_antibot(normalize_host(host.to_str().unwrap()));
         response.headers_mut().append(
             "Set-Cookie",
             HeaderValue::from_str(cookie.to_string().as_str()).unwrap(),
@@ -81,6 +101,51 @@ async fn antibot(parts: http::request::Parts) -> Result<Response<Full<Bytes>>, I
     Ok(response)
 }
 
+/**
+ * Redirect computed by the forwarder, route: {http status}/{location, base64url}
+ */
+async fn redirect(route: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mut segments = route.split('/');
+    let status = segments
+        .next()
+        .and_then(|status| status.parse::<StatusCode>().ok())
+        .unwrap_or(StatusCode::FOUND);
+    let location = segments
+        .next()
+        .and_then(|location| URL_SAFE_NO_PAD.decode(location).ok())
+        .and_then(|location| HeaderValue::from_bytes(&location).ok());
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    if let Some(location) = location {
+        response
+            .headers_mut()
+            .insert(hyper::header::LOCATION, location);
+    }
+    Ok(response)
+}
+
+/**
+ * Key authorization of an ACME HTTP-01 challenge, 404 for an unknown token
+ */
+async fn acme_challenge(
+    challenges: &AcmeChallenges,
+    token: &str,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mut response = match challenges.get(token) {
+        Some(key_authorization) => Response::new(Full::new(Bytes::from(key_authorization))),
+        None => {
+            let mut response = Response::new(Full::new(Bytes::new()));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            response
+        }
+    };
+    response.headers_mut().insert(
+        "Content-Type",
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    Ok(response)
+}
+
 pub async fn ws_upgrade_reponse(accept: String) -> Result<Response<Full<Bytes>>, Infallible> {
     let body = Full::new(Bytes::from("".to_string()));
     let mut response = Response::new(body);
@@ -103,6 +168,7 @@ pub async fn ws_upgrade_reponse(accept: String) -> Result<Response<Full<Bytes>>,
 
 async fn backend_service(
     req: Request<impl hyper::body::Body>,
+    challenges: AcmeChallenges,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
     let (parts, _body) = req.into_parts();
     //println!("route : {:?}", parts.uri);
@@ -115,6 +181,31 @@ async fn backend_service(
         {
             Ok(internal_error(InternalServerErrors::ServerUnavailable, parts).await?)
         }
+        // Unknown host, the http status follows the route
+        (_, path)
+            if path.starts_with(format!("/{}/", INTERNAL_ROUTE_ERROR_UNKNOWN_HOST).as_str()) =>
+        {
+            let status = path
+                .trim_start_matches(format!("/{}/", INTERNAL_ROUTE_ERROR_UNKNOWN_HOST).as_str())
+                .split('/')
+                .next()
+                .and_then(|status| status.parse::<StatusCode>().ok())
+                .unwrap_or(StatusCode::NOT_FOUND);
+            Ok(internal_error(InternalServerErrors::UnknownHost(status), parts).await?)
+        }
+        // Redirect, the http status and the location follow the route
+        (_, path) if path.starts_with(format!("/{}/", INTERNAL_ROUTE_REDIRECT).as_str()) => Ok(
+            redirect(path.trim_start_matches(format!("/{}/", INTERNAL_ROUTE_REDIRECT).as_str()))
+                .await?,
+        ),
+        // ACME challenge, the token follows the route
+        (Method::GET, path)
+            if path.starts_with(format!("/{}/", INTERNAL_ROUTE_ACME_CHALLENGE).as_str()) =>
+        {
+            let token =
+                path.trim_start_matches(format!("/{}/", INTERNAL_ROUTE_ACME_CHALLENGE).as_str());
+            Ok(acme_challenge(&challenges, token).await?)
+        }
         // Antibot
         (Method::GET, path)
             if path.starts_with(format!("/{}", INTERNAL_ROUTE_ANTIBOT,).as_str()) =>
@@ -133,7 +224,11 @@ async fn backend_service(
     }
 }
 
-pub async fn internal_http(name: String, addr: SocketAddr) -> Result<(), GenericError> {
+pub async fn internal_http(
+    name: String,
+    addr: SocketAddr,
+    challenges: AcmeChallenges,
+) -> Result<(), GenericError> {
     println!("Internal HTTP listener: {} is listening on: {}", name, addr);
 
     let listener = TcpListener::bind(addr).await?;
@@ -142,6 +237,7 @@ pub async fn internal_http(name: String, addr: SocketAddr) -> Result<(), Generic
         match listener.accept().await {
             Ok((tcp, _)) => {
                 let io = TokioIo::new(tcp);
+                let challenges = challenges.clone();
 
                 tokio::task::spawn(async move {
                     if let Err(err) = http1::Builder::new()
@@ -149,7 +245,10 @@ pub async fn internal_http(name: String, addr: SocketAddr) -> Result<(), Generic
                         .keep_alive(true)
                         .preserve_header_case(true)
                         .writev(true)
-                        .serve_connection(io, service_fn(backend_service))
+                        .serve_connection(
+                            io,
+                            service_fn(move |req| backend_service(req, challenges.clone())),
+                        )
                         .await
                     {
                         eprintln!("[internal listener error] {:?}", err);
@@ -1,7 +1,15 @@
+pub mod acl_matcher;
+pub mod cert_resolver;
+pub mod forwarded_headers;
 pub mod forwarder_from_http;
 pub mod forwarder_from_https;
 pub mod forwarder_handler;
 pub mod forwarder_helper;
+pub mod forwarder_tcp;
 pub mod forwarder_ws;
+pub mod header_rules;
 pub mod internal_http;
+pub mod proxy_protocol;
 pub mod servers_tracker;
+pub mod sni_passthrough;
+pub mod tracked_body;
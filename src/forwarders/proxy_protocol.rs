@@ -0,0 +1,375 @@
+use hyper::{Uri, body::Body};
+use hyper_tls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::{TokioExecutor, TokioIo},
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tower_service::Service;
+
+use crate::{
+    constants::{
+        POOL_IDLE_TIMEOUT, POOL_MAX_IDLE_PER_HOST, PROXY_PROTOCOL_READ_TIMEOUT,
+        PROXY_PROTOCOL_V1_MAX_LENGTH, PROXY_PROTOCOL_V2_SIGNATURE,
+    },
+    structs::{GenericError, ProxyProtocolVersion},
+};
+
+/**
+ * PROXY header (v1 or v2) sent by the load balancer in front of the frontend
+ * Source address of the client, None for the LOCAL (v2) and UNKNOWN (v1) connections
+ * Only the header is consumed, the TLS or HTTP stream follows
+ */
+pub async fn read_proxy_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<SocketAddr>, GenericError> {
+    tokio::time::timeout(
+        Duration::from_secs(PROXY_PROTOCOL_READ_TIMEOUT),
+        read_header(stream),
+    )
+    .await
+    .map_err(|_| GenericError::from("PROXY header: timeout"))?
+}
+
+async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<SocketAddr>, GenericError> {
+    let mut start = [0u8; 5];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY" {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= PROXY_PROTOCOL_V1_MAX_LENGTH {
+                return Err("PROXY v1 header: too long".into());
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&line);
+    }
+    let mut header = [0u8; 16];
+    header[..5].copy_from_slice(&start);
+    stream.read_exact(&mut header[5..]).await?;
+    if header[..12] != PROXY_PROTOCOL_V2_SIGNATURE {
+        return Err("missing PROXY header".into());
+    }
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    // addresses, then the TLVs (ignored)
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+    if header[12] >> 4 != 2 {
+        return Err(format!("PROXY v2 header: unsupported version {}", header[12] >> 4).into());
+    }
+    match header[12] & 0x0F {
+        // LOCAL: health check of the load balancer
+        0x0 => Ok(None),
+        0x1 => Ok(parse_v2_addresses(header[13], &addresses)),
+        command => Err(format!("PROXY v2 header: unsupported command {}", command).into()),
+    }
+}
+
+/**
+ * PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n
+ */
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, GenericError> {
+    let line = std::str::from_utf8(line)?.trim_end_matches("\r\n");
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .map_err(|e| format!("PROXY v1 header: invalid address '{}': {}", source, e))?;
+            let port = source_port
+                .parse::<u16>()
+                .map_err(|e| format!("PROXY v1 header: invalid port '{}': {}", source_port, e))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(format!("PROXY v1 header: invalid line '{}'", line).into()),
+    }
+}
+
+/**
+ * TCP or UDP over IPv4 or IPv6, None for the other families (unix sockets...)
+ */
+fn parse_v2_addresses(family: u8, addresses: &[u8]) -> Option<SocketAddr> {
+    match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = <[u8; 4]>::try_from(&addresses[..4]).ok()?;
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::from((ip, port)))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip = <[u8; 16]>::try_from(&addresses[..16]).ok()?;
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::from((ip, port)))
+        }
+        _ => None,
+    }
+}
+
+/**
+ * PROXY header for the (source, destination) of a connection,
+ * UNKNOWN (v1) or LOCAL (v2) without addresses
+ */
+pub fn build_proxy_header(
+    version: &ProxyProtocolVersion,
+    addresses: Option<(SocketAddr, SocketAddr)>,
+) -> Vec<u8> {
+    // Both addresses in the same family
+    let addresses = addresses.map(|(source, destination)| {
+        if source.is_ipv4() == destination.is_ipv4() {
+            (source, destination)
+        } else {
+            (to_ipv6(source), to_ipv6(destination))
+        }
+    });
+    match version {
+        ProxyProtocolVersion::V1 => match addresses {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = PROXY_PROTOCOL_V2_SIGNATURE.to_vec();
+            match addresses {
+                Some((source, destination)) => {
+                    // PROXY command, TCP
+                    header.push(0x21);
+                    let (family, mut body) = match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(s), IpAddr::V4(d)) => (0x11, [s.octets(), d.octets()].concat()),
+                        (s, d) => (0x21, [to_ipv6_octets(s), to_ipv6_octets(d)].concat()),
+                    };
+                    body.extend_from_slice(&source.port().to_be_bytes());
+                    body.extend_from_slice(&destination.port().to_be_bytes());
+                    header.push(family);
+                    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+                    header.extend_from_slice(&body);
+                }
+                None => {
+                    // LOCAL command, no address
+                    header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+                }
+            }
+            header
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(to_ipv6_octets(addr.ip()).into()), addr.port())
+}
+
+fn to_ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/**
+ * Http connector writing the PROXY header (if any) once the backend connection is established
+ */
+#[derive(Clone)]
+pub struct ProxyHeaderConnector {
+    http: HttpConnector,
+    version: Option<ProxyProtocolVersion>,
+    // client address, None: LOCAL/UNKNOWN (health checks)
+    source: Option<SocketAddr>,
+}
+
+impl ProxyHeaderConnector {
+    pub fn new(version: Option<ProxyProtocolVersion>, source: Option<SocketAddr>) -> Self {
+        let mut http = HttpConnector::new();
+        http.set_nodelay(true);
+        // https is handled by HttpsConnector
+        http.enforce_http(false);
+        Self {
+            http,
+            version,
+            source,
+        }
+    }
+}
+
+impl Service<Uri> for ProxyHeaderConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = GenericError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.http.call(uri);
+        let version = self.version.clone();
+        let source = self.source;
+        Box::pin(async move {
+            let mut stream = connecting.await?;
+            if let Some(version) = version {
+                let destination = stream.inner().peer_addr()?;
+                let header =
+                    build_proxy_header(&version, source.map(|source| (source, destination)));
+                stream.inner_mut().write_all(&header).await?;
+            }
+            Ok(stream)
+        })
+    }
+}
+
+/**
+ * Source address of the PROXY header: the client, behind the trusted proxies too
+ * Client port unknown behind trusted proxies
+ */
+pub fn get_proxy_source(peer_addr: SocketAddr, client_ip: IpAddr) -> SocketAddr {
+    if client_ip == peer_addr.ip() {
+        peer_addr
+    } else {
+        SocketAddr::new(client_ip, 0)
+    }
+}
+
+type ProxyProtocolClient<B> = Client<HttpsConnector<ProxyHeaderConnector>, B>;
+// client address, None: LOCAL/UNKNOWN
+type SourceClient<B> = (Option<SocketAddr>, ProxyProtocolClient<B>);
+
+/**
+ * Clients for the backend servers expecting the PROXY protocol, one per version,
+ * kept by a downstream connection or the health checker
+ * The header describes a single client: the client is replaced when the source changes
+ */
+pub struct ProxyProtocolClients<B> {
+    clients: Mutex<HashMap<ProxyProtocolVersion, SourceClient<B>>>,
+}
+
+impl<B> Default for ProxyProtocolClients<B> {
+    fn default() -> Self {
+        Self {
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<B> ProxyProtocolClients<B>
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<GenericError>,
+{
+    pub fn get(
+        &self,
+        version: &ProxyProtocolVersion,
+        source: Option<SocketAddr>,
+    ) -> ProxyProtocolClient<B> {
+        let mut clients = self.clients.lock().unwrap();
+        match clients.get(version) {
+            Some((client_source, client)) if *client_source == source => client.clone(),
+            _ => {
+                let connector = ProxyHeaderConnector::new(Some(version.clone()), source);
+                let client = Client::builder(TokioExecutor::new())
+                    .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+                    .pool_idle_timeout(Duration::from_secs(POOL_IDLE_TIMEOUT))
+                    .http1_preserve_header_case(true)
+                    .build(HttpsConnector::new_with_connector(connector));
+                clients.insert(version.clone(), (source, client.clone()));
+                client
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(source: &str, destination: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((source.parse().unwrap(), destination.parse().unwrap()))
+    }
+
+    async fn read(header: &[u8]) -> Result<Option<SocketAddr>, GenericError> {
+        let mut stream = header;
+        read_proxy_header(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn headers_are_read_back() {
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for (source, destination) in [
+                ("192.0.2.1:56324", "198.51.100.1:443"),
+                ("[2001:db8::1]:56324", "[2001:db8::2]:443"),
+            ] {
+                let header = build_proxy_header(&version, addresses(source, destination));
+                assert_eq!(
+                    read(&header).await.unwrap(),
+                    Some(source.parse().unwrap()),
+                    "{:?} {}",
+                    version,
+                    source
+                );
+            }
+            let header = build_proxy_header(&version, None);
+            assert_eq!(read(&header).await.unwrap(), None);
+        }
+        // mixed families: ipv4 mapped
+        let header = build_proxy_header(
+            &ProxyProtocolVersion::V1,
+            addresses("192.0.2.1:56324", "[2001:db8::2]:443"),
+        );
+        assert_eq!(
+            String::from_utf8(header).unwrap(),
+            "PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 56324 443\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_header_is_consumed() {
+        let mut data = build_proxy_header(
+            &ProxyProtocolVersion::V2,
+            addresses("192.0.2.1:56324", "198.51.100.1:443"),
+        );
+        // TLV
+        data.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let length = (data.len() - 16) as u16;
+        data[14..16].copy_from_slice(&length.to_be_bytes());
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut stream = data.as_slice();
+        assert_eq!(
+            read_proxy_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn invalid_headers_are_rejected() {
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1\r\n").await.is_err());
+        assert!(
+            read(&[b"PROXY ".repeat(30).as_slice(), b"\r\n"].concat())
+                .await
+                .is_err()
+        );
+        let mut header = build_proxy_header(&ProxyProtocolVersion::V2, None);
+        header[12] = 0x10;
+        assert!(read(&header).await.is_err());
+    }
+}
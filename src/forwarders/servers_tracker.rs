@@ -1,40 +1,602 @@
 use std::{
     collections::HashMap,
+    net::{IpAddr, SocketAddr},
     sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
+        Arc, LazyLock, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
     },
+    time::Instant,
 };
 
 use arc_swap::ArcSwap;
+use cookie::Cookie;
+use hyper::{HeaderMap, Method, Uri};
+use ipnet::IpNet;
+use sha1::{Digest, Sha1};
 
-use crate::structs::{BackendServer, ProxyConfig};
+use crate::{
+    constants::{
+        DEFAULT_BACKEND_ACL_NAME, DEFAULT_UNKNOWN_HOST_STATUS, HASH_RING_POINTS_PER_WEIGHT,
+        HTTP_INTERNAL_SERVER, PASSIVE_CHECK_SUCCESSES_PER_EJECTION,
+    },
+    structs::{
+        AclConfig, BackendServer, Balance, HashKey, HashSource, HeaderRules, LocationRewrite,
+        PassiveCheck, PathMatch, ProxyConfig, StickySession,
+    },
+};
+
+use super::{
+    acl_matcher::{HostMatcher, PathMatcher, RequestMatcher, normalize_host, parse_ip_ranges},
+    forwarder_helper::get_cookie_value,
+    header_rules::merge_header_rules,
+};
+
+static CLOCK_START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/**
+ * Milliseconds since the first call, monotonic
+ */
+fn get_clock_millis() -> u64 {
+    CLOCK_START.elapsed().as_millis() as u64
+}
+
+/**
+ * Runtime state of a backend server, shared by all the frontends
+ * and kept across configuration reloads
+ */
+#[derive(Debug)]
+pub struct ServerState {
+    pub name: String,
+    // Set by active health checks
+    pub healthy: AtomicBool,
+    // Passive health check
+    consecutive_failures: AtomicU32,
+    consecutive_successes: AtomicU32,
+    ejections: AtomicU32,
+    // get_clock_millis() value, 0: never ejected or reinstated
+    ejected_until: AtomicU64,
+    // In progress requests and websocket tunnels
+    active_requests: AtomicUsize,
+}
+
+impl ServerState {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            healthy: AtomicBool::new(true),
+            consecutive_failures: AtomicU32::new(0),
+            consecutive_successes: AtomicU32::new(0),
+            ejections: AtomicU32::new(0),
+            ejected_until: AtomicU64::new(0),
+            active_requests: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get_active_requests(&self) -> usize {
+        self.active_requests.load(Ordering::Relaxed)
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed) && !self.is_ejected()
+    }
+
+    /**
+     * Ejected by the passive health check, available again once the cooldown is over
+     */
+    fn is_ejected(&self) -> bool {
+        get_clock_millis() < self.ejected_until.load(Ordering::Relaxed)
+    }
+
+    /**
+     * First request outcome after the cooldown
+     */
+    fn reinstate_if_expired(&self) {
+        let until = self.ejected_until.load(Ordering::Relaxed);
+        if until != 0
+            && get_clock_millis() >= until
+            && self
+                .ejected_until
+                .compare_exchange(until, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            println!("Passive check: server {} reinstated", self.name);
+        }
+    }
+
+    /**
+     * The ejection time decays one step every PASSIVE_CHECK_SUCCESSES_PER_EJECTION
+     * successes in a row: a flapping server keeps its growing cooldown
+     */
+    pub fn report_success(&self) {
+        self.reinstate_if_expired();
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        let successes = self.consecutive_successes.fetch_add(1, Ordering::Relaxed) + 1;
+        if successes >= PASSIVE_CHECK_SUCCESSES_PER_EJECTION && !self.is_ejected() {
+            self.consecutive_successes.store(0, Ordering::Relaxed);
+            let _ =
+                self.ejections
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |ejections| {
+                        ejections.checked_sub(1)
+                    });
+        }
+    }
+
+    /**
+     * Connection error or 5xx, eject the server after max_failures in a row
+     */
+    pub fn report_failure(&self, passive_check: &PassiveCheck) {
+        if !passive_check.enabled {
+            return;
+        }
+        self.reinstate_if_expired();
+        self.consecutive_successes.store(0, Ordering::Relaxed);
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < passive_check.max_failures {
+            return;
+        }
+        let until = self.ejected_until.load(Ordering::Relaxed);
+        if get_clock_millis() < until {
+            return;
+        }
+        let ejections = self.ejections.load(Ordering::Relaxed);
+        let ejection_time = passive_check
+            .ejection_time
+            .saturating_mul(2u64.saturating_pow(ejections))
+            .min(
+                passive_check
+                    .max_ejection_time
+                    .max(passive_check.ejection_time),
+            );
+        let ejected_until = get_clock_millis().saturating_add(ejection_time.saturating_mul(1000));
+        // Ejected once by concurrent failures
+        if self
+            .ejected_until
+            .compare_exchange(until, ejected_until, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        self.ejections.fetch_add(1, Ordering::Relaxed);
+        println!(
+            "Passive check: server {} ejected for {}s after {} failures",
+            self.name, ejection_time, failures
+        );
+    }
+}
+
+/**
+ * Server name -> runtime state
+ */
+#[derive(Debug, Default)]
+pub struct ServersStates {
+    states: RwLock<HashMap<String, Arc<ServerState>>>,
+}
+
+impl ServersStates {
+    pub fn get(&self, server_name: &str) -> Arc<ServerState> {
+        if let Some(state) = self.states.read().unwrap().get(server_name) {
+            return state.clone();
+        }
+        self.states
+            .write()
+            .unwrap()
+            .entry(server_name.to_string())
+            .or_insert_with(|| Arc::new(ServerState::new(server_name.to_string())))
+            .clone()
+    }
+}
+
+/**
+ * Backend server as seen by a backend
+ */
+#[derive(Debug, Clone)]
+pub struct TrackedServer {
+    pub server: BackendServer,
+    pub state: Arc<ServerState>,
+    pub passive_check: PassiveCheck,
+    pub sticky: Option<StickySession>,
+}
+
+impl TrackedServer {
+    pub fn new(
+        server: BackendServer,
+        state: Arc<ServerState>,
+        passive_check: PassiveCheck,
+        sticky: Option<StickySession>,
+    ) -> Self {
+        Self {
+            server,
+            state,
+            passive_check,
+            sticky,
+        }
+    }
+
+    /**
+     * Sticky cookie value, a hash not to disclose the server name
+     */
+    pub fn get_sticky_id(&self) -> String {
+        format!("{:016x}", hash(&self.server.name))
+    }
+
+    /**
+     * Cookie to send when sticky sessions are on and the request
+     * does not already carry this server
+     */
+    pub fn get_sticky_cookie(&self, headers: &HeaderMap) -> Option<Cookie<'static>> {
+        let sticky = self.sticky.as_ref()?;
+        let sticky_id = self.get_sticky_id();
+        if get_cookie_value(headers, &sticky.cookie).as_deref() == Some(sticky_id.as_str()) {
+            return None;
+        }
+        let mut cookie = Cookie::build((sticky.cookie.clone(), sticky_id))
+            .path("/")
+            .http_only(true)
+            .same_site(cookie::SameSite::Lax);
+        if let Some(max_age) = sticky.max_age {
+            cookie = cookie.max_age(cookie::time::Duration::seconds(max_age));
+        }
+        Some(cookie.build())
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.server.active && self.state.is_available()
+    }
+
+    pub fn report_success(&self) {
+        self.state.report_success();
+    }
+
+    pub fn report_failure(&self) {
+        self.state.report_failure(&self.passive_check);
+    }
+
+    /**
+     * Count a request in progress until the guard is dropped
+     */
+    pub fn track_request(&self) -> RequestGuard {
+        self.state.active_requests.fetch_add(1, Ordering::Relaxed);
+        RequestGuard {
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RequestGuard {
+    state: Arc<ServerState>,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.state.active_requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/**
+ * What the acls and the balancing may depend on
+ */
+pub struct BalanceContext<'a> {
+    pub client_ip: IpAddr,
+    pub method: &'a Method,
+    pub headers: &'a HeaderMap,
+    pub uri: &'a Uri,
+}
+
+impl BalanceContext<'_> {
+    fn get_hash_key(&self, hash_key: &HashKey) -> Option<String> {
+        let name = hash_key.name.as_deref().unwrap_or("");
+        match hash_key.source {
+            HashSource::Ip => Some(self.client_ip.to_string()),
+            HashSource::Path => Some(self.uri.path().to_string()),
+            HashSource::Header => self
+                .headers
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_string()),
+            HashSource::Cookie => get_cookie_value(self.headers, name),
+        }
+    }
+}
+
+fn hash(value: &str) -> u64 {
+    let digest = Sha1::digest(value.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/**
+ * Servers of a backend and the balancing state
+ */
+#[derive(Debug)]
+pub struct BackendPool {
+    pub servers: Vec<TrackedServer>,
+    balance: Balance,
+    hash_key: HashKey,
+    // Smooth weighted round robin current weights, one per server
+    current_weights: Mutex<Vec<i64>>,
+    // Least connections: rotating start to share ties
+    next_start: AtomicUsize,
+    // Consistent hash ring (ketama): sorted (point, server index)
+    ring: Vec<(u64, usize)>,
+}
+
+impl BackendPool {
+    pub fn new(servers: Vec<TrackedServer>, balance: Balance, hash_key: HashKey) -> Self {
+        let current_weights = Mutex::new(vec![0; servers.len()]);
+        let ring = if balance == Balance::Hash {
+            Self::build_ring(&servers)
+        } else {
+            Vec::new()
+        };
+        Self {
+            servers,
+            balance,
+            hash_key,
+            current_weights,
+            next_start: AtomicUsize::new(0),
+            ring,
+        }
+    }
+
+    /**
+     * Points depend on server names only: adding or removing a server
+     * only moves the keys of the points it owns
+     */
+    fn build_ring(servers: &[TrackedServer]) -> Vec<(u64, usize)> {
+        let mut ring = servers
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, tracked)| {
+                // weight bounded by validate_config (MAX_SERVER_WEIGHT)
+                (0..u64::from(tracked.server.weight) * u64::from(HASH_RING_POINTS_PER_WEIGHT))
+                    .map(move |point| (hash(&format!("{}#{}", tracked.server.name, point)), idx))
+            })
+            .collect::<Vec<_>>();
+        ring.sort_unstable();
+        ring
+    }
+
+    /**
+     * Next server not inactive, unhealthy or ejected, according to the balance mode
+     * None only when every server of the backend is down
+     */
+    pub fn get_next_server(&self, context: &BalanceContext) -> Option<TrackedServer> {
+        if let Some(tracked) = self.get_sticky_server(context) {
+            return Some(tracked);
+        }
+        match self.balance {
+            Balance::RoundRobin => self.get_next_weighted_round_robin(),
+            Balance::LeastConn => self.get_least_connections(),
+            // No key (header or cookie missing): round robin
+            Balance::Hash => match context.get_hash_key(&self.hash_key) {
+                Some(key) => self.get_consistent_hash(&key),
+                None => self.get_next_weighted_round_robin(),
+            },
+        }
+    }
+
+    /**
+     * Server of the sticky cookie, None when the cookie is missing
+     * or its server no longer available
+     */
+    fn get_sticky_server(&self, context: &BalanceContext) -> Option<TrackedServer> {
+        let sticky = self.servers.first()?.sticky.as_ref()?;
+        let sticky_id = get_cookie_value(context.headers, &sticky.cookie)?;
+        self.servers
+            .iter()
+            .find(|tracked| tracked.get_sticky_id() == sticky_id)
+            .filter(|tracked| Self::is_eligible(tracked))
+            .cloned()
+    }
+
+    /**
+     * First point of the ring after the key hash whose server is available
+     */
+    fn get_consistent_hash(&self, key: &str) -> Option<TrackedServer> {
+        let len = self.ring.len();
+        let start = self.ring.partition_point(|(point, _)| *point < hash(key));
+        (0..len)
+            .map(|i| &self.servers[self.ring[(start + i) % len].1])
+            .find(|tracked| Self::is_eligible(tracked))
+            .cloned()
+    }
+
+    fn is_eligible(tracked: &TrackedServer) -> bool {
+        tracked.is_available() && tracked.server.weight > 0
+    }
+
+    /**
+     * Smooth weighted round robin (nginx): each available server gains its weight,
+     * the highest is picked and loses the total
+     */
+    fn get_next_weighted_round_robin(&self) -> Option<TrackedServer> {
+        let mut current_weights = self.current_weights.lock().unwrap();
+        let mut total = 0;
+        let mut selected: Option<usize> = None;
+        for (idx, tracked) in self.servers.iter().enumerate() {
+            if !Self::is_eligible(tracked) {
+                continue;
+            }
+            let weight = tracked.server.weight as i64;
+            current_weights[idx] += weight;
+            total += weight;
+            if selected.is_none_or(|s| current_weights[idx] > current_weights[s]) {
+                selected = Some(idx);
+            }
+        }
+        let selected = selected?;
+        current_weights[selected] -= total;
+        Some(self.servers[selected].clone())
+    }
+
+    /**
+     * Lowest requests in progress / weight, ties are shared in turn
+     */
+    fn get_least_connections(&self) -> Option<TrackedServer> {
+        let len = self.servers.len();
+        let start = self.next_start.fetch_add(1, Ordering::Relaxed);
+        let mut selected: Option<(&TrackedServer, u64)> = None;
+        for i in 0..len {
+            let tracked = &self.servers[(start + i) % len];
+            if !Self::is_eligible(tracked) {
+                continue;
+            }
+            let load = tracked.state.get_active_requests() as u64;
+            let weight = tracked.server.weight as u64;
+            // load / weight < selected_load / selected_weight
+            if selected.is_none_or(|(s, s_load)| load * (s.server.weight as u64) < s_load * weight)
+            {
+                selected = Some((tracked, load));
+            }
+        }
+        selected.map(|(tracked, _)| tracked.clone())
+    }
+}
+
+/**
+ * Acl and the servers of its backend
+ */
+#[derive(Debug)]
+pub struct Route {
+    pub acl: AclConfig,
+    path_matcher: Option<PathMatcher>,
+    request_matcher: Option<RequestMatcher>,
+    // rules of the backend then of the acl
+    pub header_rules: HeaderRules,
+    pub pool: BackendPool,
+}
+
+impl Route {
+    pub fn is_match(&self, context: &BalanceContext) -> bool {
+        self.path_matcher
+            .as_ref()
+            .is_none_or(|matcher| matcher.is_match(context.uri.path()))
+            && self
+                .request_matcher
+                .as_ref()
+                .is_none_or(|matcher| matcher.is_match(context))
+    }
+
+    /**
+     * Path and query to forward, rewritten if the acl asks for it
+     */
+    pub fn get_path_and_query(&self, uri: &Uri) -> String {
+        let path = uri.path();
+        let path = match (&self.path_matcher, &self.acl.path) {
+            (
+                Some(matcher),
+                Some(PathMatch {
+                    rewrite: Some(rewrite),
+                    ..
+                }),
+            ) => matcher.rewrite(path, rewrite),
+            _ => path.to_string(),
+        };
+        match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        }
+    }
+}
+
+/**
+ * Acls sharing the same host condition
+ */
+#[derive(Debug)]
+pub struct HostRoutes {
+    host: String,
+    host_matcher: HostMatcher,
+    // evaluation order
+    pub routes: Vec<Route>,
+}
 
 #[derive(Debug)]
 pub struct ServerTracker {
-    pub backends: HashMap<String, (Vec<BackendServer>, AtomicUsize)>,
+    // exact host -> routes
+    pub routes: HashMap<String, HostRoutes>,
+    // wildcard and regex hosts in evaluation order
+    pub patterns: Vec<HostRoutes>,
+    // requests matching no acl
+    pub default_route: Option<Route>,
+    pub unknown_host_status: u16,
+    // port of the https frontend all the requests are redirected to
+    pub https_redirect_port: Option<u16>,
+    pub frontend_tls: bool,
+    pub frontend_port: u16,
+    pub trusted_proxies: Vec<IpNet>,
+    // ClientHello to be read before the TLS handshake
+    pub has_passthrough: bool,
+    // internal server answering the errors, redirects and challenges
+    pub internal_server: SocketAddr,
 }
 
 impl ServerTracker {
     pub fn new() -> Self {
         Self {
-            backends: HashMap::new(),
+            routes: HashMap::new(),
+            patterns: Vec::new(),
+            default_route: None,
+            unknown_host_status: DEFAULT_UNKNOWN_HOST_STATUS,
+            https_redirect_port: None,
+            frontend_tls: false,
+            frontend_port: 0,
+            trusted_proxies: Vec::new(),
+            has_passthrough: false,
+            internal_server: SocketAddr::from(([127, 0, 0, 1], HTTP_INTERNAL_SERVER)),
         }
     }
 
-    pub fn get_next_backend(&self, host: &str) -> Option<BackendServer> {
-        // Get natural next backend
-        let final_server = self.backends.get(host).map(|(servers, idx)| {
-            let next_idx = idx.fetch_add(1, Ordering::Relaxed);
-            let server = servers[next_idx % servers.len()].clone();
-            server
-        });
-        // if server, check is active?
-        if final_server.is_some() && !final_server.clone().unwrap().active {
-            None
-        } else {
-            final_server
+    /**
+     * First acl of the host matching the request, else the default backend
+     * host: raw Host header, case and :port are ignored
+     */
+    pub fn get_route(&self, host: &str, context: &BalanceContext) -> Option<&Route> {
+        self.get_host_routes(host)
+            .find_map(|host_routes| {
+                host_routes
+                    .routes
+                    .iter()
+                    .find(|route| !route.acl.passthrough && route.is_match(context))
+            })
+            .or(self.default_route.as_ref())
+    }
+
+    /**
+     * Passthrough acl of the TLS server name, None: TLS terminated by the proxy
+     * A host with a terminated acl for all its requests (no path or condition) is never passed through
+     */
+    pub fn get_passthrough_route(&self, server_name: &str) -> Option<&Route> {
+        for host_routes in self.get_host_routes(server_name) {
+            if let Some(route) = host_routes
+                .routes
+                .iter()
+                .find(|route| route.acl.passthrough)
+            {
+                return Some(route);
+            }
+            if host_routes
+                .routes
+                .iter()
+                .any(|route| route.acl.path.is_none() && route.acl.when.is_none())
+            {
+                return None;
+            }
         }
+        None
+    }
+
+    /**
+     * Acls of the exact host, then of each matching wildcard or regex, in evaluation order
+     * A group without a matching acl falls through to the next one
+     */
+    fn get_host_routes(&self, host: &str) -> impl Iterator<Item = &HostRoutes> {
+        let host = normalize_host(host);
+        self.routes.get(&host).into_iter().chain(
+            self.patterns
+                .iter()
+                .filter(move |host_routes| host_routes.host_matcher.is_match(&host)),
+        )
     }
 
     // pub fn get_first_backend(&self, host: &str) -> Option<BackendServer> {
@@ -46,42 +608,1084 @@ impl ServerTracker {
     /**
      * Build structure servers_tracker
      * Server tracker table is set per frontend_name
-     * ([domain/path],[server1,server2,...]
+     * ([domain],[[path],[server1,server2,...]])
      */
-    pub fn populate(&mut self, frontend_name: String, config: Arc<ArcSwap<ProxyConfig>>) {
+    pub fn populate(
+        &mut self,
+        frontend_name: String,
+        config: Arc<ArcSwap<ProxyConfig>>,
+        servers_states: &ServersStates,
+    ) {
         let cfg = config.load().clone();
         // get backends
-        let pool_lookup: HashMap<_, _> = cfg
-            .pool_backends
-            .iter()
-            .map(|pb| (&pb.name, &pb.servers))
-            .collect();
-        // Process frontend
-        let lookup_table = cfg
-            // filter frontend on frontend_name
-            .frontends
-            .iter()
-            .find(|f| f.name == frontend_name)
-            // Get acls
+        let pool_lookup: HashMap<_, _> =
+            cfg.pool_backends.iter().map(|pb| (&pb.name, pb)).collect();
+        let frontend = cfg.frontends.iter().find(|f| f.name == frontend_name);
+        let build_route = |acl: &AclConfig| {
+            // redirect: no backend involved
+            let backend = match (pool_lookup.get(&acl.backend), &acl.redirect) {
+                (Some(backend), _) => Some(*backend),
+                (None, Some(_)) => None,
+                (None, None) => return None,
+            };
+            Some({
+                let passive_check = backend
+                    .and_then(|backend| backend.passive_check.clone())
+                    .unwrap_or_default();
+                let servers = cfg
+                    .pool_servers
+                    .iter()
+                    .filter(|server| backend.is_some_and(|b| b.servers.contains(&server.name)))
+                    .map(|server| {
+                        TrackedServer::new(
+                            server.clone(),
+                            servers_states.get(&server.name),
+                            passive_check.clone(),
+                            backend.and_then(|backend| backend.sticky.clone()),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                // regex and conditions checked by validate_config
+                let path_matcher = acl
+                    .path
+                    .as_ref()
+                    .and_then(|path| PathMatcher::new(path).ok());
+                let request_matcher = acl
+                    .when
+                    .as_ref()
+                    .and_then(|when| RequestMatcher::new(when).ok());
+                Route {
+                    acl: acl.clone(),
+                    path_matcher,
+                    request_matcher,
+                    header_rules: merge_header_rules(
+                        backend.and_then(|backend| backend.headers.as_ref()),
+                        acl.headers.as_ref(),
+                    ),
+                    pool: BackendPool::new(
+                        servers,
+                        backend
+                            .map(|backend| backend.balance.clone())
+                            .unwrap_or_default(),
+                        backend
+                            .and_then(|backend| backend.hash_key.clone())
+                            .unwrap_or_default(),
+                    ),
+                }
+            })
+        };
+        // Process frontend acls
+        let lookup_table = frontend
             .into_iter()
             .flat_map(|frontend| &frontend.acls)
             // finally build tracker content
-            .filter_map(|acl| {
-                pool_lookup.get(&acl.backend).map(|servers| {
-                    (
-                        acl.host.clone(),
-                        cfg.pool_servers
-                            .iter()
-                            .filter(|server| servers.contains(&server.name))
-                            .cloned()
-                            .collect::<Vec<_>>(),
-                    )
+            .filter_map(build_route)
+            .collect::<Vec<_>>();
+        for route in lookup_table {
+            // regex checked by validate_config
+            let Ok(host_matcher) = HostMatcher::new(&route.acl.host) else {
+                continue;
+            };
+            let host_routes = match &host_matcher {
+                HostMatcher::Exact(host) => self.routes.get_mut(host),
+                _ => self
+                    .patterns
+                    .iter_mut()
+                    .find(|host_routes| host_routes.host == route.acl.host),
+            };
+            match host_routes {
+                Some(host_routes) => host_routes.routes.push(route),
+                None => {
+                    let host_routes = HostRoutes {
+                        host: route.acl.host.clone(),
+                        host_matcher,
+                        routes: vec![route],
+                    };
+                    match &host_routes.host_matcher {
+                        HostMatcher::Exact(host) => {
+                            self.routes.insert(host.clone(), host_routes);
+                        }
+                        _ => self.patterns.push(host_routes),
+                    }
+                }
+            }
+        }
+        // Stable sort: same priority keeps the config order
+        self.patterns
+            .sort_by_key(|host_routes| host_routes.host_matcher.get_priority());
+        for host_routes in self.routes.values_mut().chain(self.patterns.iter_mut()) {
+            host_routes.routes.sort_by_key(|route| {
+                (
+                    PathMatcher::get_priority(&route.path_matcher),
+                    // acls with conditions before the catch-all of the same path
+                    route.request_matcher.is_none(),
+                )
+            });
+        }
+        // catch-all acl on the default backend
+        self.default_route = frontend.and_then(|frontend| {
+            frontend.default_backend.as_ref().and_then(|backend| {
+                build_route(&AclConfig {
+                    name: DEFAULT_BACKEND_ACL_NAME.to_string(),
+                    host: "*".to_string(),
+                    path: None,
+                    when: None,
+                    backend: backend.clone(),
+                    antibot: None,
+                    redirect: None,
+                    rewrite_location: LocationRewrite::default(),
+                    headers: None,
+                    passthrough: false,
                 })
             })
+        });
+        if let Some(frontend) = frontend {
+            self.unknown_host_status = frontend.unknown_host_status;
+            self.frontend_tls = frontend.tls;
+            self.frontend_port = frontend.port;
+            self.has_passthrough = frontend.tls && frontend.acls.iter().any(|acl| acl.passthrough);
+            // checked by validate_config
+            self.trusted_proxies = parse_ip_ranges(&frontend.trusted_proxies).unwrap_or_default();
+            self.https_redirect_port = frontend.https_redirect.as_ref().and_then(|name| {
+                cfg.frontends
+                    .iter()
+                    .find(|f| &f.name == name)
+                    .map(|f| f.port)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::AclCondition;
+    use hyper::Request;
+    use std::net::Ipv4Addr;
+
+    const FRONTEND: &str = "frontend-http";
+    const HOST: &str = "www.domain.com";
+
+    fn build_config(servers: &[(&str, bool, u32)]) -> Arc<ArcSwap<ProxyConfig>> {
+        let mut yaml = format!(
+            r#"
+frontends:
+  - name: "{FRONTEND}"
+    protocol: "http"
+    addr: "0.0.0.0"
+    port: 3000
+    tls: false
+    active: true
+    acls:
+      - name: "host_www"
+        host: "{HOST}"
+        backend: "k8s_www"
+pool_backends:
+  - name: "k8s_www"
+    servers: [{}]
+pool_servers:
+"#,
+            servers
+                .iter()
+                .map(|(name, _, _)| format!("\"{}\"", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        for (index, (name, active, weight)) in servers.iter().enumerate() {
+            yaml.push_str(&format!(
+                r#"  - name: "{name}"
+    host: "172.0.0.{index}"
+    port: 31222
+    protocol: "http"
+    tls: false
+    active: {active}
+    weight: {weight}
+"#
+            ));
+        }
+        let config: ProxyConfig = serde_yaml::from_str(&yaml).unwrap();
+        Arc::new(ArcSwap::new(Arc::new(config)))
+    }
+
+    fn build_tracker(servers: &[(&str, bool, u32)], states: &ServersStates) -> ServerTracker {
+        let mut tracker = ServerTracker::new();
+        tracker.populate(FRONTEND.to_string(), build_config(servers), states);
+        tracker
+    }
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn route_for<'a>(
+        tracker: &'a ServerTracker,
+        host: &str,
+        request: Request<()>,
+        client_ip: IpAddr,
+    ) -> Option<&'a Route> {
+        tracker.get_route(
+            host,
+            &BalanceContext {
+                client_ip,
+                method: request.method(),
+                headers: request.headers(),
+                uri: request.uri(),
+            },
+        )
+    }
+
+    fn next_server(pool: &BackendPool, key: Option<&str>) -> Option<TrackedServer> {
+        let mut headers = HeaderMap::new();
+        if let Some(key) = key {
+            headers.insert("x-user", key.parse().unwrap());
+        }
+        let uri = Uri::from_static("/");
+        pool.get_next_server(&BalanceContext {
+            client_ip: IpAddr::from([127, 0, 0, 1]),
+            method: &Method::GET,
+            headers: &headers,
+            uri: &uri,
+        })
+    }
+
+    fn next_backend(tracker: &ServerTracker, host: &str) -> Option<TrackedServer> {
+        route_for(
+            tracker,
+            host,
+            Request::get("/").body(()).unwrap(),
+            LOCALHOST,
+        )
+        .and_then(|route| next_server(&route.pool, None))
+    }
+
+    fn pick(tracker: &ServerTracker, count: usize) -> HashMap<String, usize> {
+        let mut picked = HashMap::new();
+        for _ in 0..count {
+            let tracked = next_backend(tracker, HOST).unwrap();
+            *picked.entry(tracked.server.name).or_insert(0) += 1;
+        }
+        picked
+    }
+
+    #[test]
+    fn all_active_servers_are_used_in_turn() {
+        let tracker = build_tracker(
+            &[("s0", true, 1), ("s1", true, 1)],
+            &ServersStates::default(),
+        );
+        let names = (0..4)
+            .map(|_| next_backend(&tracker, HOST).unwrap().server.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["s0", "s1", "s0", "s1"]);
+    }
+
+    #[test]
+    fn inactive_server_is_skipped() {
+        // Sample config: one of two servers inactive
+        let tracker = build_tracker(
+            &[("s0", true, 1), ("s1", false, 1)],
+            &ServersStates::default(),
+        );
+        let picked = pick(&tracker, 10);
+        assert_eq!(picked.get("s0"), Some(&10));
+        assert_eq!(picked.get("s1"), None);
+    }
+
+    #[test]
+    fn traffic_is_shared_evenly_between_available_servers() {
+        let tracker = build_tracker(
+            &[
+                ("s0", true, 1),
+                ("s1", false, 1),
+                ("s2", true, 1),
+                ("s3", true, 1),
+            ],
+            &ServersStates::default(),
+        );
+        let picked = pick(&tracker, 30);
+        assert_eq!(picked.get("s0"), Some(&10));
+        assert_eq!(picked.get("s1"), None);
+        assert_eq!(picked.get("s2"), Some(&10));
+        assert_eq!(picked.get("s3"), Some(&10));
+    }
+
+    #[test]
+    fn weighted_servers_are_picked_smoothly() {
+        let tracker = build_tracker(
+            &[("s0", true, 5), ("s1", true, 1), ("s2", true, 1)],
+            &ServersStates::default(),
+        );
+        let names = (0..7)
+            .map(|_| next_backend(&tracker, HOST).unwrap().server.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["s0", "s0", "s1", "s0", "s2", "s0", "s0"]);
+    }
+
+    #[test]
+    fn traffic_is_proportional_to_weight() {
+        let tracker = build_tracker(
+            &[
+                ("s0", true, 3),
+                ("s1", false, 5),
+                ("s2", true, 1),
+                ("s3", true, 0),
+            ],
+            &ServersStates::default(),
+        );
+        let picked = pick(&tracker, 40);
+        assert_eq!(picked.get("s0"), Some(&30));
+        assert_eq!(picked.get("s1"), None);
+        assert_eq!(picked.get("s2"), Some(&10));
+        assert_eq!(picked.get("s3"), None);
+    }
+
+    fn build_pool(
+        servers: &[(&str, u32)],
+        states: &ServersStates,
+        balance: Balance,
+    ) -> BackendPool {
+        build_sticky_pool(servers, states, balance, None)
+    }
+
+    fn build_sticky_pool(
+        servers: &[(&str, u32)],
+        states: &ServersStates,
+        balance: Balance,
+        sticky: Option<StickySession>,
+    ) -> BackendPool {
+        let config = build_config(
+            &servers
+                .iter()
+                .map(|(name, weight)| (*name, true, *weight))
+                .collect::<Vec<_>>(),
+        );
+        let servers = config
+            .load()
+            .pool_servers
+            .iter()
+            .map(|server| {
+                TrackedServer::new(
+                    server.clone(),
+                    states.get(&server.name),
+                    PassiveCheck::default(),
+                    sticky.clone(),
+                )
+            })
+            .collect();
+        let hash_key = HashKey {
+            source: HashSource::Header,
+            name: Some("x-user".to_string()),
+        };
+        BackendPool::new(servers, balance, hash_key)
+    }
+
+    #[test]
+    fn least_conn_picks_the_least_loaded_server() {
+        let states = ServersStates::default();
+        let pool = build_pool(
+            &[("s0", 1), ("s1", 1), ("s2", 1)],
+            &states,
+            Balance::LeastConn,
+        );
+        let _guards = [
+            pool.servers[0].track_request(),
+            pool.servers[0].track_request(),
+            pool.servers[1].track_request(),
+        ];
+        assert_eq!(next_server(&pool, None).unwrap().server.name, "s2");
+        // s2 busy too, s1 and s2 are equal and less loaded than s0
+        let _guard = pool.servers[2].track_request();
+        for _ in 0..4 {
+            assert_ne!(next_server(&pool, None).unwrap().server.name, "s0");
+        }
+    }
+
+    #[test]
+    fn least_conn_releases_requests_and_uses_weight() {
+        let states = ServersStates::default();
+        let pool = build_pool(&[("s0", 3), ("s1", 1)], &states, Balance::LeastConn);
+        let guards = (0..2)
+            .map(|_| pool.servers[0].track_request())
+            .collect::<Vec<_>>();
+        let guard = pool.servers[1].track_request();
+        // 2/3 < 1/1
+        assert_eq!(next_server(&pool, None).unwrap().server.name, "s0");
+        drop(guard);
+        assert_eq!(states.get("s1").get_active_requests(), 0);
+        assert_eq!(next_server(&pool, None).unwrap().server.name, "s1");
+        drop(guards);
+        assert_eq!(states.get("s0").get_active_requests(), 0);
+    }
+
+    #[test]
+    fn unhealthy_and_ejected_servers_are_skipped() {
+        let states = ServersStates::default();
+        let tracker = build_tracker(
+            &[("s0", true, 1), ("s1", true, 1), ("s2", true, 1)],
+            &states,
+        );
+        states.get("s0").healthy.store(false, Ordering::Relaxed);
+        let passive_check = PassiveCheck {
+            max_failures: 1,
+            ..PassiveCheck::default()
+        };
+        states.get("s1").report_failure(&passive_check);
+        let picked = pick(&tracker, 5);
+        assert_eq!(picked.get("s2"), Some(&5));
+    }
+
+    /**
+     * Cooldown left to the ejected server, in seconds
+     */
+    fn ejected_for(state: &ServerState) -> u64 {
+        let left = state
+            .ejected_until
+            .load(Ordering::Relaxed)
+            .saturating_sub(get_clock_millis());
+        left.div_ceil(1000)
+    }
+
+    /**
+     * Cooldown over
+     */
+    fn reinstate(state: &ServerState) {
+        state
+            .ejected_until
+            .store(get_clock_millis(), Ordering::Relaxed);
+        assert!(state.is_available());
+    }
+
+    #[test]
+    fn flapping_server_ejection_time_grows() {
+        let state = ServerState::new("s0".to_string());
+        let passive_check = PassiveCheck {
+            max_failures: 1,
+            ejection_time: 10,
+            max_ejection_time: 300,
+            ..PassiveCheck::default()
+        };
+        // One request answered between the failures
+        for ejection_time in [10, 20, 40, 80] {
+            state.report_failure(&passive_check);
+            assert_eq!(ejected_for(&state), ejection_time);
+            reinstate(&state);
+            state.report_success();
+            assert_eq!(state.ejected_until.load(Ordering::Relaxed), 0);
+        }
+        // Healthy again: the ejection time decays
+        for _ in 0..2 * PASSIVE_CHECK_SUCCESSES_PER_EJECTION {
+            state.report_success();
+        }
+        state.report_failure(&passive_check);
+        assert_eq!(ejected_for(&state), 40);
+    }
+
+    #[test]
+    fn no_server_when_all_are_down() {
+        let states = ServersStates::default();
+        let tracker = build_tracker(&[("s0", false, 1), ("s1", true, 1)], &states);
+        states.get("s1").healthy.store(false, Ordering::Relaxed);
+        assert!(next_backend(&tracker, HOST).is_none());
+        // Back again
+        states.get("s1").healthy.store(true, Ordering::Relaxed);
+        assert_eq!(next_backend(&tracker, HOST).unwrap().server.name, "s1");
+    }
+
+    #[test]
+    fn unknown_host_or_empty_backend_has_no_server() {
+        let tracker = build_tracker(&[], &ServersStates::default());
+        assert!(next_backend(&tracker, HOST).is_none());
+        assert!(next_backend(&tracker, "unknown.domain.com").is_none());
+    }
+
+    fn hash_mapping(pool: &BackendPool) -> Vec<String> {
+        (0..1000)
+            .map(|i| {
+                next_server(pool, Some(&format!("user-{}", i)))
+                    .unwrap()
+                    .server
+                    .name
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hash_is_sticky_and_spread() {
+        let states = ServersStates::default();
+        let pool = build_pool(&[("s0", 1), ("s1", 1), ("s2", 1)], &states, Balance::Hash);
+        let mapping = hash_mapping(&pool);
+        assert_eq!(mapping, hash_mapping(&pool));
+        for name in ["s0", "s1", "s2"] {
+            let count = mapping.iter().filter(|n| *n == name).count();
+            assert!(count > 200, "{} got {} keys", name, count);
+        }
+    }
+
+    #[test]
+    fn hash_only_remaps_keys_of_a_removed_server() {
+        let states = ServersStates::default();
+        let pool = build_pool(
+            &[("s0", 1), ("s1", 1), ("s2", 1), ("s3", 1)],
+            &states,
+            Balance::Hash,
+        );
+        let before = hash_mapping(&pool);
+        states.get("s1").healthy.store(false, Ordering::Relaxed);
+        let after = hash_mapping(&pool);
+        for (before, after) in before.iter().zip(after.iter()) {
+            assert_ne!(after, "s1");
+            if before != "s1" {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn hash_adding_a_server_moves_a_small_fraction_of_keys() {
+        let states = ServersStates::default();
+        let before = hash_mapping(&build_pool(
+            &[("s0", 1), ("s1", 1), ("s2", 1), ("s3", 1)],
+            &states,
+            Balance::Hash,
+        ));
+        let after = hash_mapping(&build_pool(
+            &[("s0", 1), ("s1", 1), ("s2", 1), ("s3", 1), ("s4", 1)],
+            &states,
+            Balance::Hash,
+        ));
+        let moved = before.iter().zip(after.iter()).filter(|(b, a)| b != a);
+        // Ideally 1/5 of the keys, all to the new server
+        assert!(moved.clone().all(|(_, a)| a == "s4"));
+        assert!(moved.count() < 300);
+    }
+
+    #[test]
+    fn hash_without_key_falls_back_to_round_robin() {
+        let states = ServersStates::default();
+        let pool = build_pool(&[("s0", 1), ("s1", 1)], &states, Balance::Hash);
+        let names = (0..4)
+            .map(|_| next_server(&pool, None).unwrap().server.name)
             .collect::<Vec<_>>();
-        for (host, backends) in lookup_table {
-            self.backends
-                .insert(host.clone(), (backends.clone(), AtomicUsize::new(0)));
+        assert_eq!(names, vec!["s0", "s1", "s0", "s1"]);
+    }
+
+    fn next_sticky_server(pool: &BackendPool, cookie: Option<&str>) -> TrackedServer {
+        let mut headers = HeaderMap::new();
+        if let Some(cookie) = cookie {
+            headers.insert(
+                "cookie",
+                format!("other=1; srv={}", cookie).parse().unwrap(),
+            );
         }
+        let uri = Uri::from_static("/");
+        pool.get_next_server(&BalanceContext {
+            client_ip: IpAddr::from([127, 0, 0, 1]),
+            method: &Method::GET,
+            headers: &headers,
+            uri: &uri,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn sticky_cookie_routes_to_the_same_server() {
+        let states = ServersStates::default();
+        let sticky = StickySession {
+            cookie: "srv".to_string(),
+            max_age: None,
+        };
+        let pool = build_sticky_pool(
+            &[("s0", 1), ("s1", 1), ("s2", 1)],
+            &states,
+            Balance::RoundRobin,
+            Some(sticky),
+        );
+        // First request: balanced, cookie to set
+        let first = next_sticky_server(&pool, None);
+        let cookie = first.get_sticky_cookie(&HeaderMap::new()).unwrap();
+        assert_eq!(cookie.name(), "srv");
+        assert_ne!(cookie.value(), first.server.name);
+        // Next requests: same server, no cookie to set again
+        for _ in 0..5 {
+            let next = next_sticky_server(&pool, Some(cookie.value()));
+            assert_eq!(next.server.name, first.server.name);
+            let mut headers = HeaderMap::new();
+            headers.insert("cookie", format!("srv={}", cookie.value()).parse().unwrap());
+            assert!(next.get_sticky_cookie(&headers).is_none());
+        }
+        // Server down: balanced again, a new cookie is set
+        states
+            .get(&first.server.name)
+            .healthy
+            .store(false, Ordering::Relaxed);
+        let next = next_sticky_server(&pool, Some(cookie.value()));
+        assert_ne!(next.server.name, first.server.name);
+        assert!(next.get_sticky_cookie(&HeaderMap::new()).is_some());
+        // Unknown value: balanced
+        next_sticky_server(&pool, Some("unknown"));
+    }
+
+    #[test]
+    fn no_sticky_cookie_when_not_configured() {
+        let states = ServersStates::default();
+        let pool = build_pool(&[("s0", 1)], &states, Balance::RoundRobin);
+        let tracked = next_server(&pool, None).unwrap();
+        assert!(tracked.get_sticky_cookie(&HeaderMap::new()).is_none());
+    }
+
+    fn build_routes_tracker(acls: &str) -> ServerTracker {
+        let yaml = format!(
+            r#"
+frontends:
+  - name: "{FRONTEND}"
+    protocol: "http"
+    addr: "0.0.0.0"
+    port: 3000
+    tls: false
+    active: true
+    acls:
+{acls}
+pool_backends:
+  - name: "b0"
+    servers: ["s0"]
+  - name: "b1"
+    servers: ["s1"]
+  - name: "b2"
+    servers: ["s2"]
+pool_servers:
+  - name: "s0"
+    host: "172.0.0.0"
+    port: 31222
+    protocol: "http"
+    tls: false
+    active: true
+  - name: "s1"
+    host: "172.0.0.1"
+    port: 31222
+    protocol: "http"
+    tls: false
+    active: true
+  - name: "s2"
+    host: "172.0.0.2"
+    port: 31222
+    protocol: "http"
+    tls: false
+    active: true
+"#
+        );
+        let config: ProxyConfig = serde_yaml::from_str(&yaml).unwrap();
+        let mut tracker = ServerTracker::new();
+        tracker.populate(
+            FRONTEND.to_string(),
+            Arc::new(ArcSwap::new(Arc::new(config))),
+            &ServersStates::default(),
+        );
+        tracker
+    }
+
+    fn route_backend(tracker: &ServerTracker, path: &str) -> Option<String> {
+        route_for(
+            tracker,
+            HOST,
+            Request::get(path).body(()).unwrap(),
+            LOCALHOST,
+        )
+        .map(|route| route.acl.backend.clone())
+    }
+
+    #[test]
+    fn path_routes_are_ordered_by_precision() {
+        let tracker = build_routes_tracker(&format!(
+            r#"      - {{ name: "default", host: "{HOST}", backend: "b0" }}
+      - {{ name: "api", host: "{HOST}", backend: "b1", path: {{ type: "prefix", value: "/api" }} }}
+      - {{ name: "api_v2", host: "{HOST}", backend: "b2", path: {{ type: "prefix", value: "/api/v2" }} }}
+      - {{ name: "health", host: "{HOST}", backend: "b0", path: {{ type: "exact", value: "/api/health" }} }}"#
+        ));
+        assert_eq!(route_backend(&tracker, "/").as_deref(), Some("b0"));
+        assert_eq!(route_backend(&tracker, "/api/users").as_deref(), Some("b1"));
+        assert_eq!(
+            route_backend(&tracker, "/api/v2/users").as_deref(),
+            Some("b2")
+        );
+        assert_eq!(
+            route_backend(&tracker, "/api/health").as_deref(),
+            Some("b0")
+        );
+    }
+
+    #[test]
+    fn prefix_matches_whole_path_segments() {
+        let tracker = build_routes_tracker(&format!(
+            r#"      - {{ name: "default", host: "{HOST}", backend: "b0" }}
+      - {{ name: "api", host: "{HOST}", backend: "b1", path: {{ type: "prefix", value: "/api", rewrite: "/" }} }}
+      - {{ name: "static", host: "{HOST}", backend: "b2", path: {{ type: "prefix", value: "/static/" }} }}"#
+        ));
+        assert_eq!(route_backend(&tracker, "/api").as_deref(), Some("b1"));
+        assert_eq!(route_backend(&tracker, "/api/users").as_deref(), Some("b1"));
+        assert_eq!(route_backend(&tracker, "/apiary").as_deref(), Some("b0"));
+        assert_eq!(
+            route_backend(&tracker, "/static/app.js").as_deref(),
+            Some("b2")
+        );
+        assert_eq!(route_backend(&tracker, "/static").as_deref(), Some("b0"));
+        // not rewritten as /ary
+        let route = route_for(
+            &tracker,
+            HOST,
+            Request::get("/apiary").body(()).unwrap(),
+            LOCALHOST,
+        )
+        .unwrap();
+        assert_eq!(
+            route.get_path_and_query(&Uri::from_static("/apiary")),
+            "/apiary"
+        );
+    }
+
+    #[test]
+    fn regex_routes_keep_config_order() {
+        let tracker = build_routes_tracker(&format!(
+            r#"      - {{ name: "images", host: "{HOST}", backend: "b1", path: {{ type: "regex", value: "\\.(png|jpg)$" }} }}
+      - {{ name: "assets", host: "{HOST}", backend: "b2", path: {{ type: "regex", value: "^/assets/" }} }}"#
+        ));
+        assert_eq!(
+            route_backend(&tracker, "/assets/logo.png").as_deref(),
+            Some("b1")
+        );
+        assert_eq!(
+            route_backend(&tracker, "/assets/app.js").as_deref(),
+            Some("b2")
+        );
+        // No default acl
+        assert_eq!(route_backend(&tracker, "/index.html"), None);
+    }
+
+    #[test]
+    fn path_is_rewritten() {
+        let tracker = build_routes_tracker(&format!(
+            r#"      - {{ name: "api", host: "{HOST}", backend: "b1", path: {{ type: "prefix", value: "/api", rewrite: "/" }} }}
+      - {{ name: "legacy", host: "{HOST}", backend: "b2", path: {{ type: "regex", value: "^/old/(.*)$", rewrite: "/new/$1" }} }}"#
+        ));
+        let rewrite = |uri: &'static str| {
+            route_for(
+                &tracker,
+                HOST,
+                Request::get(uri).body(()).unwrap(),
+                LOCALHOST,
+            )
+            .unwrap()
+            .get_path_and_query(&Uri::from_static(uri))
+        };
+        assert_eq!(rewrite("/api/users?id=1"), "/users?id=1");
+        assert_eq!(rewrite("/api"), "/");
+        assert_eq!(rewrite("/old/page"), "/new/page");
+    }
+
+    fn host_backend(tracker: &ServerTracker, host: &str) -> Option<String> {
+        route_for(
+            tracker,
+            host,
+            Request::get("/").body(()).unwrap(),
+            LOCALHOST,
+        )
+        .map(|route| route.acl.backend.clone())
+    }
+
+    #[test]
+    fn host_ignores_case_and_port() {
+        let tracker = build_routes_tracker(&format!(
+            r#"      - {{ name: "www", host: "{HOST}", backend: "b0" }}"#
+        ));
+        assert_eq!(
+            host_backend(&tracker, "www.domain.com").as_deref(),
+            Some("b0")
+        );
+        assert_eq!(
+            host_backend(&tracker, "WWW.Domain.COM").as_deref(),
+            Some("b0")
+        );
+        assert_eq!(
+            host_backend(&tracker, "www.domain.com:8443").as_deref(),
+            Some("b0")
+        );
+        assert_eq!(host_backend(&tracker, "api.domain.com"), None);
+    }
+
+    #[test]
+    fn wildcard_and_regex_hosts() {
+        let tracker = build_routes_tracker(
+            r#"      - { name: "regex", host: "~^api[0-9]+\\.domain\\.com$", backend: "b2" }
+      - { name: "any", host: "*.domain.com", backend: "b0" }
+      - { name: "eu", host: "*.eu.domain.com", backend: "b1" }
+      - { name: "www", host: "www.eu.domain.com", backend: "b2" }"#,
+        );
+        assert_eq!(
+            host_backend(&tracker, "shop.domain.com").as_deref(),
+            Some("b0")
+        );
+        // longest wildcard wins
+        assert_eq!(
+            host_backend(&tracker, "shop.eu.domain.com:443").as_deref(),
+            Some("b1")
+        );
+        // exact before wildcard
+        assert_eq!(
+            host_backend(&tracker, "www.eu.domain.com").as_deref(),
+            Some("b2")
+        );
+        // wildcard before regex
+        assert_eq!(
+            host_backend(&tracker, "API1.domain.com").as_deref(),
+            Some("b0")
+        );
+        // wildcard needs a subdomain
+        assert_eq!(host_backend(&tracker, "domain.com"), None);
+    }
+
+    #[test]
+    fn unmatched_host_acls_fall_through_to_the_patterns() {
+        let tracker = build_routes_tracker(
+            r#"      - { name: "www-api", host: "www.domain.com", backend: "b1", path: { type: "prefix", value: "/api" } }
+      - { name: "any", host: "*.domain.com", backend: "b0" }
+      - { name: "crm-admin", host: "crm.apps.domain.com", backend: "b2", path: { type: "prefix", value: "/admin" } }
+      - { name: "www-apps", host: "www.apps.domain.com", backend: "b2" }
+      - { name: "apps", host: "*.apps.domain.com", backend: "b1", passthrough: true }"#,
+        );
+        let backend = |host: &str, path: &str| {
+            route_for(
+                &tracker,
+                host,
+                Request::get(path).body(()).unwrap(),
+                LOCALHOST,
+            )
+            .map(|route| route.acl.backend.clone())
+        };
+        assert_eq!(
+            backend("www.domain.com", "/api/users").as_deref(),
+            Some("b1")
+        );
+        assert_eq!(backend("www.domain.com", "/").as_deref(), Some("b0"));
+        let passthrough_backend = |server_name: &str| {
+            tracker
+                .get_passthrough_route(server_name)
+                .map(|route| route.acl.backend.clone())
+        };
+        assert_eq!(
+            passthrough_backend("crm.apps.domain.com").as_deref(),
+            Some("b1")
+        );
+        // terminated for all its requests
+        assert_eq!(passthrough_backend("www.apps.domain.com"), None);
+    }
+
+    #[test]
+    fn regex_host_is_case_insensitive() {
+        let tracker = build_routes_tracker(
+            r#"      - { name: "regex", host: "~^api[0-9]+\\.domain\\.com$", backend: "b2" }"#,
+        );
+        assert_eq!(
+            host_backend(&tracker, "API12.Domain.com:8080").as_deref(),
+            Some("b2")
+        );
+        assert_eq!(host_backend(&tracker, "api.domain.com"), None);
+    }
+
+    fn request_backend(
+        tracker: &ServerTracker,
+        request: hyper::http::request::Builder,
+        client_ip: [u8; 4],
+    ) -> Option<String> {
+        route_for(
+            tracker,
+            HOST,
+            request.body(()).unwrap(),
+            IpAddr::from(client_ip),
+        )
+        .map(|route| route.acl.backend.clone())
+    }
+
+    #[test]
+    fn conditions_select_the_acl() {
+        let tracker = build_routes_tracker(&format!(
+            r#"      - name: "default"
+        host: "{HOST}"
+        backend: "b0"
+      - name: "canary"
+        host: "{HOST}"
+        backend: "b1"
+        when:
+          any:
+            - header: {{ name: "X-Canary", value: "1" }}
+            - cookie: {{ name: "canary" }}
+            - query: {{ name: "canary", value: "yes" }}
+      - name: "internal"
+        host: "{HOST}"
+        backend: "b2"
+        when:
+          all:
+            - source: ["10.0.0.0/8", "192.168.1.10"]
+            - method: ["get", "HEAD"]
+            - not:
+                header: {{ name: "x-public" }}"#
+        ));
+        let get = |uri: &str| Request::get(uri);
+        assert_eq!(
+            request_backend(&tracker, get("/"), [1, 2, 3, 4]).as_deref(),
+            Some("b0")
+        );
+        assert_eq!(
+            request_backend(&tracker, get("/").header("x-canary", "1"), [1, 2, 3, 4]).as_deref(),
+            Some("b1")
+        );
+        assert_eq!(
+            request_backend(&tracker, get("/").header("x-canary", "0"), [1, 2, 3, 4]).as_deref(),
+            Some("b0")
+        );
+        assert_eq!(
+            request_backend(
+                &tracker,
+                get("/").header("cookie", "a=1; canary=x"),
+                [1, 2, 3, 4]
+            )
+            .as_deref(),
+            Some("b1")
+        );
+        assert_eq!(
+            request_backend(&tracker, get("/?a=1&canary=yes"), [1, 2, 3, 4]).as_deref(),
+            Some("b1")
+        );
+        assert_eq!(
+            request_backend(&tracker, get("/"), [10, 1, 2, 3]).as_deref(),
+            Some("b2")
+        );
+        assert_eq!(
+            request_backend(&tracker, get("/"), [192, 168, 1, 10]).as_deref(),
+            Some("b2")
+        );
+        assert_eq!(
+            request_backend(&tracker, get("/"), [192, 168, 1, 11]).as_deref(),
+            Some("b0")
+        );
+        assert_eq!(
+            request_backend(&tracker, Request::post("/"), [10, 1, 2, 3]).as_deref(),
+            Some("b0")
+        );
+        assert_eq!(
+            request_backend(&tracker, get("/").header("X-Public", "1"), [10, 1, 2, 3]).as_deref(),
+            Some("b0")
+        );
+    }
+
+    #[test]
+    fn invalid_conditions_are_rejected() {
+        let condition = |yaml: &str| {
+            let condition: AclCondition = serde_yaml::with::singleton_map_recursive::deserialize(
+                serde_yaml::Deserializer::from_str(yaml),
+            )
+            .unwrap();
+            RequestMatcher::new(&condition)
+        };
+        assert!(condition("source: [\"10.0.0.0/8\"]").is_ok());
+        assert!(condition("source: [\"10.0.0.300/8\"]").is_err());
+        assert!(condition("header: { name: \"bad header\" }").is_err());
+        assert!(condition("not: { method: [\"GE T\"] }").is_err());
+    }
+
+    #[test]
+    fn unmatched_requests_use_the_default_backend() {
+        let acls = format!(
+            r#"      - {{ name: "api", host: "{HOST}", backend: "b1", path: {{ type: "prefix", value: "/api" }} }}"#
+        );
+        let tracker = build_routes_tracker(&acls);
+        assert_eq!(host_backend(&tracker, "other.domain.com"), None);
+        assert_eq!(route_backend(&tracker, "/"), None);
+        assert_eq!(tracker.unknown_host_status, 404);
+
+        let tracker = build_routes_tracker(&format!(
+            "{acls}\n    default_backend: \"b2\"\n    unknown_host_status: 421"
+        ));
+        assert_eq!(route_backend(&tracker, "/api/users").as_deref(), Some("b1"));
+        assert_eq!(route_backend(&tracker, "/").as_deref(), Some("b2"));
+        assert_eq!(
+            host_backend(&tracker, "other.domain.com").as_deref(),
+            Some("b2")
+        );
+        assert_eq!(tracker.unknown_host_status, 421);
+    }
+
+    #[test]
+    fn redirects_are_answered_without_backend() {
+        use crate::forwarders::forwarder_helper::get_redirect;
+        let tracker = build_routes_tracker(&format!(
+            r#"      - name: "old"
+        host: "old.domain.com"
+        redirect: {{ to: "https://{HOST}", status: 301, preserve_path: true }}
+      - name: "docs"
+        host: "{HOST}"
+        path: {{ type: "prefix", value: "/docs" }}
+        redirect: {{ to: "https://docs.domain.com/?from={{host}}{{path}}&{{query}}" }}"#
+        ));
+        let redirect = |host: &str, uri: &'static str| {
+            let route = route_for(
+                &tracker,
+                host,
+                Request::get(uri).body(()).unwrap(),
+                LOCALHOST,
+            );
+            assert!(route.is_some_and(|route| route.pool.servers.is_empty()));
+            get_redirect(
+                &tracker,
+                route.map(|route| &route.acl),
+                host,
+                &Uri::from_static(uri),
+            )
+        };
+        assert_eq!(
+            redirect("old.domain.com:8080", "/a/b?c=d"),
+            Some((301, "https://www.domain.com/a/b?c=d".to_string()))
+        );
+        assert_eq!(
+            redirect(HOST, "/docs/x?y=1"),
+            Some((
+                302,
+                "https://docs.domain.com/?from=www.domain.com/docs/x&y=1".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn http_frontend_redirects_to_https() {
+        use crate::forwarders::forwarder_helper::get_redirect;
+        let mut tracker = build_routes_tracker("      []");
+        let uri = Uri::from_static("/a?b=c");
+        assert_eq!(get_redirect(&tracker, None, HOST, &uri), None);
+        tracker.https_redirect_port = Some(443);
+        assert_eq!(
+            get_redirect(&tracker, None, "www.domain.com:80", &uri),
+            Some((308, "https://www.domain.com/a?b=c".to_string()))
+        );
+        tracker.https_redirect_port = Some(8443);
+        assert_eq!(
+            get_redirect(&tracker, None, HOST, &uri),
+            Some((308, "https://www.domain.com:8443/a?b=c".to_string()))
+        );
+    }
+
+    #[test]
+    fn passthrough_acls_are_not_http_routes() {
+        let tracker = build_routes_tracker(
+            r#"      - { name: "apps", host: "*.apps.domain.com", backend: "b1", passthrough: true }
+      - { name: "www", host: "www.domain.com", backend: "b0" }"#,
+        );
+        let passthrough_backend = |server_name: &str| {
+            tracker
+                .get_passthrough_route(server_name)
+                .map(|route| route.acl.backend.clone())
+        };
+        assert_eq!(
+            passthrough_backend("crm.apps.domain.com").as_deref(),
+            Some("b1")
+        );
+        assert_eq!(passthrough_backend("www.domain.com"), None);
+        // never served as http
+        assert_eq!(host_backend(&tracker, "crm.apps.domain.com"), None);
     }
 }
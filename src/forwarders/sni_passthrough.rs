@@ -0,0 +1,267 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::{
+    constants::{TLS_CLIENT_HELLO_MAX_RECORDS, TLS_CLIENT_HELLO_READ_TIMEOUT},
+    structs::GenericError,
+};
+
+// TLS record: content type (handshake), version, length
+const RECORD_HEADER_LENGTH: usize = 5;
+const RECORD_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+
+/**
+ * Read the records of the TLS ClientHello, without decrypting anything
+ * Raw bytes read (to be replayed) and the server name (SNI) if any
+ */
+pub async fn read_client_hello<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<(Vec<u8>, Option<String>), GenericError> {
+    tokio::time::timeout(
+        Duration::from_secs(TLS_CLIENT_HELLO_READ_TIMEOUT),
+        read_records(stream),
+    )
+    .await
+    .map_err(|_| GenericError::from("TLS ClientHello: timeout"))?
+}
+
+async fn read_records<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<(Vec<u8>, Option<String>), GenericError> {
+    let mut raw = Vec::new();
+    // handshake message, possibly split over several records
+    let mut handshake = Vec::new();
+    for _ in 0..TLS_CLIENT_HELLO_MAX_RECORDS {
+        let mut header = [0u8; RECORD_HEADER_LENGTH];
+        stream.read_exact(&mut header).await?;
+        raw.extend_from_slice(&header);
+        if header[0] != RECORD_TYPE_HANDSHAKE {
+            // Not TLS: left to the handshake to fail
+            return Ok((raw, None));
+        }
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let start = raw.len();
+        raw.resize(start + length, 0);
+        stream.read_exact(&mut raw[start..]).await?;
+        handshake.extend_from_slice(&raw[start..]);
+        if handshake.len() >= 4 {
+            let message_length =
+                u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= 4 + message_length {
+                return Ok((raw, parse_server_name(&handshake)));
+            }
+        }
+    }
+    Ok((raw, None))
+}
+
+/**
+ * server_name extension of a ClientHello handshake message
+ */
+fn parse_server_name(handshake: &[u8]) -> Option<String> {
+    let mut reader = Reader(handshake);
+    if reader.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return None;
+    }
+    // length, version, random
+    reader.skip(3 + 2 + 32)?;
+    // session id, cipher suites, compression methods
+    let length = reader.u8()? as usize;
+    reader.skip(length)?;
+    let length = reader.u16()? as usize;
+    reader.skip(length)?;
+    let length = reader.u8()? as usize;
+    reader.skip(length)?;
+    let length = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(length)?);
+    while let (Some(extension_type), Some(length)) = (extensions.u16(), extensions.u16()) {
+        let data = extensions.take(length as usize)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader(data);
+        let length = names.u16()? as usize;
+        let mut names = Reader(names.take(length)?);
+        while let Some(name_type) = names.u8() {
+            let length = names.u16()? as usize;
+            let name = names.take(length)?;
+            // host_name
+            if name_type == 0 {
+                return std::str::from_utf8(name)
+                    .ok()
+                    .map(|name| name.to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (value, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(value)
+    }
+
+    fn skip(&mut self, length: usize) -> Option<()> {
+        self.take(length).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|value| value[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|value| u16::from_be_bytes([value[0], value[1]]))
+    }
+}
+
+/**
+ * Stream replaying the bytes already read (ClientHello) before the rest of the connection
+ */
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position < self.prefix.len() {
+            let length = buf.remaining().min(self.prefix.len() - self.position);
+            let start = self.position;
+            buf.put_slice(&self.prefix[start..start + length]);
+            self.position += length;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * TLS 1.2 ClientHello record with the server_name extension
+     */
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let name = server_name.as_bytes();
+        let mut server_name_extension = vec![0x00, 0x00];
+        server_name_extension.extend_from_slice(&((name.len() + 5) as u16).to_be_bytes());
+        server_name_extension.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        server_name_extension.push(0x00);
+        server_name_extension.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        server_name_extension.extend_from_slice(name);
+        // supported_versions before server_name
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend_from_slice(&server_name_extension);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x42; 32]);
+        // session id, cipher suites, compression
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+        handshake
+    }
+
+    fn records(handshake: &[u8], record_length: usize) -> Vec<u8> {
+        handshake
+            .chunks(record_length)
+            .flat_map(|chunk| {
+                let mut record = vec![RECORD_TYPE_HANDSHAKE, 0x03, 0x01];
+                record.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                record.extend_from_slice(chunk);
+                record
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn server_name_is_read_and_replayed() {
+        for record_length in [16384, 20] {
+            let mut data = records(&client_hello("App.Domain.com"), record_length);
+            let hello_length = data.len();
+            data.extend_from_slice(b"next");
+            let mut stream = data.as_slice();
+            let (raw, server_name) = read_client_hello(&mut stream).await.unwrap();
+            assert_eq!(server_name.as_deref(), Some("app.domain.com"));
+            assert_eq!(raw.len(), hello_length);
+            assert_eq!(stream, b"next");
+
+            let mut replayed = Vec::new();
+            PrefixedStream::new(raw, stream)
+                .read_to_end(&mut replayed)
+                .await
+                .unwrap();
+            assert_eq!(replayed, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn no_server_name() {
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        let (raw, server_name) = read_client_hello(&mut stream).await.unwrap();
+        assert_eq!(raw, b"GET /");
+        assert_eq!(server_name, None);
+        // truncated extensions
+        let mut hello = client_hello("app.domain.com");
+        hello.truncate(hello.len() - 4);
+        hello[3] -= 4;
+        let data = records(&hello, 16384);
+        assert_eq!(
+            read_client_hello(&mut data.as_slice()).await.unwrap().1,
+            None
+        );
+    }
+}
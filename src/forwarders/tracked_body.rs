@@ -0,0 +1,47 @@
+use bytes::Bytes;
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::servers_tracker::RequestGuard;
+
+/**
+ * Backend response body, the backend server request counter is released
+ * when the body is complete (or dropped)
+ */
+pub struct TrackedBody {
+    inner: Incoming,
+    guard: Option<RequestGuard>,
+}
+
+impl TrackedBody {
+    pub fn new(inner: Incoming, guard: Option<RequestGuard>) -> Self {
+        Self { inner, guard }
+    }
+}
+
+impl Body for TrackedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(None) = frame {
+            self.guard.take();
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,174 @@
+use arc_swap::ArcSwap;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    forwarders::{
+        cert_resolver::Certificates,
+        forwarder_from_http::proxy_from_http,
+        forwarder_from_https::proxy_from_https,
+        forwarder_tcp::proxy_from_tcp,
+        servers_tracker::{ServerTracker, ServersStates},
+    },
+    health_checker::HealthChecker,
+    structs::{FrontEnd, ProxyConfig, ProxyProtocols},
+};
+
+pub fn parse_bind_address(input: &str) -> Result<IpAddr, String> {
+    input
+        .parse()
+        .map_err(|e| format!("Invalid IP address '{}': {}", input, e))
+}
+
+struct RunningFrontend {
+    frontend: FrontEnd,
+    servers_tracker: Arc<ArcSwap<ServerTracker>>,
+    task: JoinHandle<()>,
+}
+
+/**
+ * Keep the running listeners in line with the frontends of the config
+ */
+pub struct FrontendsManager {
+    config: Arc<ArcSwap<ProxyConfig>>,
+    certificates: Arc<ArcSwap<Certificates>>,
+    running: HashMap<String, RunningFrontend>,
+    servers_states: Arc<ServersStates>,
+    health_checker: HealthChecker,
+}
+
+impl FrontendsManager {
+    pub fn new(
+        config: Arc<ArcSwap<ProxyConfig>>,
+        certificates: Arc<ArcSwap<Certificates>>,
+    ) -> Self {
+        let servers_states = Arc::new(ServersStates::default());
+        Self {
+            health_checker: HealthChecker::new(config.clone(), servers_states.clone()),
+            config,
+            certificates,
+            running: HashMap::new(),
+            servers_states,
+        }
+    }
+
+    /**
+     * Start, stop or restart listeners according to the current config
+     * Listeners whose bind parameters are unchanged only get their tracker repopulated
+     */
+    pub async fn apply(&mut self) {
+        self.health_checker.apply();
+        let frontends = self.config.load().frontends.clone();
+        // Stop removed, disabled or rebound frontends
+        let to_stop = self
+            .running
+            .iter()
+            .filter(|(name, running)| {
+                !frontends.iter().any(|f| {
+                    &f.name == *name && f.active && !is_rebind_needed(&running.frontend, f)
+                })
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in to_stop {
+            self.stop(&name).await;
+        }
+        for frontend in frontends {
+            if !frontend.active {
+                continue;
+            }
+            let tracker = self.build_tracker(&frontend.name);
+            match self.running.get_mut(&frontend.name) {
+                Some(running) => {
+                    // Same listener, refresh acls -> servers
+                    running.servers_tracker.store(Arc::new(tracker));
+                    running.frontend = frontend;
+                }
+                None => {
+                    let running = self.start(frontend, tracker);
+                    self.running.insert(running.frontend.name.clone(), running);
+                }
+            }
+        }
+    }
+
+    fn build_tracker(&self, frontend_name: &str) -> ServerTracker {
+        let mut tracker = ServerTracker::new();
+        tracker.populate(
+            frontend_name.to_string(),
+            self.config.clone(),
+            &self.servers_states,
+        );
+        tracker
+    }
+
+    fn start(&self, frontend: FrontEnd, tracker: ServerTracker) -> RunningFrontend {
+        // addr has been checked by validate_config
+        let ipaddr = parse_bind_address(&frontend.addr).unwrap();
+        let addr = SocketAddr::from((ipaddr, frontend.port));
+        let cfg = self.config.clone();
+        let servers_tracker = Arc::new(ArcSwap::new(Arc::new(tracker)));
+        let tracker = servers_tracker.clone();
+        let name = frontend.name.clone();
+        let task = if frontend.protocol == ProxyProtocols::Tcp {
+            // Frontend tcp, tls termination when tls is set
+            let certificates = frontend.tls.then(|| self.certificates.clone());
+            tokio::spawn(async move {
+                if let Err(e) = proxy_from_tcp(cfg, certificates, tracker, name.clone(), addr).await
+                {
+                    eprintln!("Frontend {} crashed: {}", name, e);
+                }
+            })
+        } else if frontend.tls {
+            // Frontend https
+            let certificates = self.certificates.clone();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    proxy_from_https(cfg, certificates, tracker, name.clone(), addr).await
+                {
+                    eprintln!("Frontend {} crashed: {}", name, e);
+                }
+            })
+        } else {
+            // Frontend http
+            tokio::spawn(async move {
+                if let Err(e) = proxy_from_http(cfg, tracker, name.clone(), addr).await {
+                    eprintln!("Frontend {} crashed: {}", name, e);
+                }
+            })
+        };
+        RunningFrontend {
+            frontend,
+            servers_tracker,
+            task,
+        }
+    }
+
+    /**
+     * Stop accepting new connections, connections already accepted run
+     * in their own tasks and are left to complete
+     */
+    async fn stop(&mut self, name: &str) {
+        if let Some(running) = self.running.remove(name) {
+            running.task.abort();
+            // Wait for the listener to be dropped, the port may be reused right away
+            let _ = running.task.await;
+            println!("Frontend {} stopped", name);
+        }
+    }
+}
+
+/**
+ * Listener must be recreated when its bind or tls parameters change
+ */
+fn is_rebind_needed(current: &FrontEnd, new: &FrontEnd) -> bool {
+    current.addr != new.addr
+        || current.port != new.port
+        || current.tls != new.tls
+        || current.protocol != new.protocol
+        || current.default_certificate != new.default_certificate
+}
@@ -0,0 +1,270 @@
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::{Request, Uri};
+use hyper_tls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
+use tokio::{net::TcpStream, task::JoinHandle, time::timeout};
+
+use crate::{
+    forwarders::{
+        forwarder_helper::build_upstream_uri, proxy_protocol::ProxyProtocolClients,
+        servers_tracker::ServersStates,
+    },
+    structs::{BackendServer, HealthCheck, HealthCheckType, ProxyConfig},
+};
+
+type HealthCheckClient = Client<HttpsConnector<HttpConnector>, Empty<Bytes>>;
+
+/**
+ * Run the active health checks declared on backends
+ * A server shared by several backends is checked once, with the first health_check found
+ */
+pub struct HealthChecker {
+    config: Arc<ArcSwap<ProxyConfig>>,
+    servers_states: Arc<ServersStates>,
+    client: HealthCheckClient,
+    // LOCAL/UNKNOWN connections
+    proxy_clients: Arc<ProxyProtocolClients<Empty<Bytes>>>,
+    // server name -> running check, with the settings it was started with
+    tasks: HashMap<String, (BackendServer, HealthCheck, JoinHandle<()>)>,
+}
+
+impl HealthChecker {
+    pub fn new(config: Arc<ArcSwap<ProxyConfig>>, servers_states: Arc<ServersStates>) -> Self {
+        Self {
+            config,
+            servers_states,
+            client: Client::builder(TokioExecutor::new()).build(HttpsConnector::new()),
+            proxy_clients: Arc::new(ProxyProtocolClients::default()),
+            tasks: HashMap::new(),
+        }
+    }
+
+    /**
+     * (Re)start the checks according to the current config: only the changed ones,
+     * the others keep their rise/fall counters
+     * Servers no longer checked are considered healthy
+     */
+    pub fn apply(&mut self) {
+        let config = self.config.load();
+        let mut checks: HashMap<String, (BackendServer, HealthCheck)> = HashMap::new();
+        for backend in &config.pool_backends {
+            if let Some(health_check) = &backend.health_check {
+                for server in config
+                    .pool_servers
+                    .iter()
+                    .filter(|s| backend.servers.contains(&s.name))
+                {
+                    checks
+                        .entry(server.name.clone())
+                        .or_insert_with(|| (server.clone(), health_check.clone()));
+                }
+            }
+        }
+        for server in &config.pool_servers {
+            if !checks.contains_key(&server.name) {
+                self.servers_states
+                    .get(&server.name)
+                    .healthy
+                    .store(true, Ordering::Relaxed);
+            }
+        }
+        self.tasks.retain(|name, (server, health_check, task)| {
+            let unchanged = checks
+                .get(name)
+                .is_some_and(|(new_server, new_health_check)| {
+                    new_server == server && new_health_check == health_check
+                });
+            if !unchanged {
+                task.abort();
+            }
+            unchanged
+        });
+        for (name, (server, health_check)) in checks {
+            if self.tasks.contains_key(&name) {
+                continue;
+            }
+            let servers_states = self.servers_states.clone();
+            let client = self.client.clone();
+            let proxy_clients = self.proxy_clients.clone();
+            let task = tokio::spawn({
+                let server = server.clone();
+                let health_check = health_check.clone();
+                async move {
+                    run_health_check(server, health_check, servers_states, client, proxy_clients)
+                        .await;
+                }
+            });
+            self.tasks.insert(name, (server, health_check, task));
+        }
+    }
+}
+
+async fn run_health_check(
+    server: BackendServer,
+    health_check: HealthCheck,
+    servers_states: Arc<ServersStates>,
+    client: HealthCheckClient,
+    proxy_clients: Arc<ProxyProtocolClients<Empty<Bytes>>>,
+) {
+    let state = servers_states.get(&server.name);
+    let mut interval = tokio::time::interval(Duration::from_secs(health_check.interval));
+    let mut successes = 0;
+    let mut failures = 0;
+    loop {
+        interval.tick().await;
+        let result = check_server(&server, &health_check, &client, &proxy_clients).await;
+        let healthy = state.healthy.load(Ordering::Relaxed);
+        match result {
+            Ok(()) => {
+                failures = 0;
+                successes += 1;
+                if !healthy && successes >= health_check.rise {
+                    state.healthy.store(true, Ordering::Relaxed);
+                    println!("Health check: server {} is UP", server.name);
+                }
+            }
+            Err(e) => {
+                successes = 0;
+                failures += 1;
+                if healthy && failures >= health_check.fall {
+                    state.healthy.store(false, Ordering::Relaxed);
+                    println!("Health check: server {} is DOWN: {}", server.name, e);
+                }
+            }
+        }
+    }
+}
+
+async fn check_server(
+    server: &BackendServer,
+    health_check: &HealthCheck,
+    client: &HealthCheckClient,
+    proxy_clients: &ProxyProtocolClients<Empty<Bytes>>,
+) -> Result<(), String> {
+    let duration = Duration::from_secs(health_check.timeout);
+    match health_check.check_type {
+        HealthCheckType::Tcp => {
+            timeout(
+                duration,
+                TcpStream::connect((server.host.as_str(), server.port)),
+            )
+            .await
+            .map_err(|_| "timeout".to_string())?
+            .map_err(|e| e.to_string())?;
+            Ok(())
+        }
+        HealthCheckType::Http => {
+            let uri = format!(
+                "{}{}",
+                build_upstream_uri(server.clone(), false),
+                health_check.path
+            )
+            .parse::<Uri>()
+            .map_err(|e| e.to_string())?;
+            let req = Request::get(uri)
+                .header("Host", server.host.as_str())
+                .body(Empty::new())
+                .map_err(|e| e.to_string())?;
+            let request = match &server.send_proxy_protocol {
+                // LOCAL/UNKNOWN connection
+                Some(version) => proxy_clients.get(version, None).request(req),
+                None => client.request(req),
+            };
+            let response = timeout(duration, request)
+                .await
+                .map_err(|_| "timeout".to_string())?
+                .map_err(|e| e.to_string())?;
+            if response.status().as_u16() == health_check.expected_status {
+                Ok(())
+            } else {
+                Err(format!("status {}", response.status()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU16;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /**
+     * Stub server answering every request with the current status
+     */
+    async fn start_stub_server(status: Arc<AtomicU16>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let status = status.load(Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let response = format!(
+                        "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        port
+    }
+
+    /**
+     * Wait for the server state, the checker re-applied (unchanged config) meanwhile
+     */
+    async fn wait_healthy(checker: &mut HealthChecker, healthy: bool) {
+        let state = checker.servers_states.get("s0");
+        for _ in 0..20 {
+            if state.healthy.load(Ordering::Relaxed) == healthy {
+                return;
+            }
+            checker.apply();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+        panic!("server s0 never became healthy: {}", healthy);
+    }
+
+    #[tokio::test]
+    async fn server_goes_down_after_fall_and_up_after_rise() {
+        let status = Arc::new(AtomicU16::new(200));
+        let port = start_stub_server(status.clone()).await;
+        let yaml = format!(
+            r#"
+frontends: []
+pool_backends:
+  - name: "b"
+    servers: ["s0"]
+    health_check: {{ type: "http", path: "/health", interval: 1, timeout: 1, rise: 2, fall: 2 }}
+pool_servers:
+  - {{ name: "s0", host: "127.0.0.1", port: {port}, protocol: "http", tls: false, active: true }}
+"#
+        );
+        let config: ProxyConfig = serde_yaml::from_str(&yaml).unwrap();
+        let mut checker = HealthChecker::new(
+            Arc::new(ArcSwap::from_pointee(config)),
+            Arc::new(ServersStates::default()),
+        );
+        checker.apply();
+        status.store(503, Ordering::Relaxed);
+        wait_healthy(&mut checker, false).await;
+        status.store(200, Ordering::Relaxed);
+        wait_healthy(&mut checker, true).await;
+    }
+}
@@ -10,7 +10,7 @@ pub fn template_html_internal_error(
 <head>
     <meta charset="UTF-8">
     <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>Service Unavailable</title>
+    <title>ERRORCODE</title>
     <style>
         body {
             font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif;
@@ -87,10 +87,10 @@ pub fn template_html_internal_error(
 </html>
 "#;
     let html = String::from(HTML_TEMPLATE).clone();
-    html.replace("ERRORCODE", &error_code.as_str())
-        .replace("P1", &p1.as_str())
-        .replace("P2", &p2.as_str())
-        .replace("url_redirect", &redirect.as_str())
+    html.replace("ERRORCODE", error_code.as_str())
+        .replace("P1", p1.as_str())
+        .replace("P2", p2.as_str())
+        .replace("url_redirect", redirect.as_str())
 }
 
 pub fn template_html_antibot(redirect: String) -> String {
@@ -176,5 +176,5 @@ pub fn template_html_antibot(redirect: String) -> String {
 </html>
 "#;
     let html = String::from(HTML_TEMPLATE).clone();
-    html.replace("PATHREFRESH", &redirect.as_str())
+    html.replace("PATHREFRESH", redirect.as_str())
 }
@@ -1,28 +1,32 @@
+mod acme_client;
+mod acme_manager;
+mod api_rest;
+mod certs_manager;
 mod config_manager;
 mod constants;
 mod forwarders;
+mod frontends_manager;
+mod health_checker;
 mod html;
 mod structs;
 
-use arc_swap::ArcSwap;
+use acme_client::AcmeChallenges;
+use acme_manager::AcmeManager;
+use api_rest::{ApiState, api_rest};
+use certs_manager::CertsManager;
 use clap::Parser;
 use config_manager::{Args, ConfigManager};
-use constants::HTTP_INTERNAL_SERVER;
-use forwarders::forwarder_from_http::proxy_from_http;
-use forwarders::forwarder_from_https::proxy_from_https;
+use constants::{CERTS_WATCH_INTERVAL, CONFIG_WATCH_INTERVAL, HTTP_INTERNAL_SERVER};
 use forwarders::internal_http::internal_http;
-use forwarders::servers_tracker::ServerTracker;
+use frontends_manager::{FrontendsManager, parse_bind_address};
 use structs::GenericError;
 
-use std::net::{IpAddr, SocketAddr};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use std::sync::Arc;
-
-fn parse_bind_address(input: &str) -> Result<IpAddr, String> {
-    input
-        .parse()
-        .map_err(|e| format!("Invalid IP address '{}': {}", input, e))
-}
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::Mutex,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), GenericError> {
@@ -31,85 +35,115 @@ async fn main() -> Result<(), GenericError> {
     config_manager.load().await?;
     let config = config_manager.get_config().await;
     let certs_path = config_manager.get_config_tls_certs_path().await;
-    let mut listeners = Vec::new();
+    let api_addr = config_manager.get_api_addr().await?;
+    let api_token = config_manager.get_api_token().await;
+    let config_manager = Arc::new(Mutex::new(config_manager));
+    // Certificates shared by the tls frontends
+    let mut certs_manager = CertsManager::new(certs_path.clone());
+    if let Err(e) = certs_manager.load() {
+        eprintln!("Tls certificates not loaded: {}", e);
+    }
+    let certificates = certs_manager.get_certificates();
+    let certs_manager = Arc::new(Mutex::new(certs_manager));
     // Starting frontends
-    for frontend in config.load().as_ref().clone().frontends {
-        if !frontend.active {
-            continue;
-        }
-        // Clone init configuratin
-        let ipaddr = parse_bind_address(&frontend.addr).unwrap();
-        let addr = SocketAddr::from((ipaddr, frontend.port));
-        let cfg = config.clone();
-        let server_task: tokio::task::JoinHandle<()>;
-        let certs_path = certs_path.clone();
-        if frontend.tls {
-            // Frontend https
-            server_task = tokio::spawn(async move {
-                let servers_tracker: Arc<arc_swap::ArcSwapAny<Arc<ServerTracker>>> =
-                    Arc::new(ArcSwap::new({
-                        let mut tracker = ServerTracker::new();
-                        tracker.populate(frontend.clone().name, cfg.clone());
-                        Arc::new(tracker)
-                    }));
-                let certs_path = certs_path.clone();
+    let frontends_manager = Arc::new(Mutex::new(FrontendsManager::new(
+        config.clone(),
+        certificates,
+    )));
+    frontends_manager.lock().await.apply().await;
 
-                if let Err(e) = proxy_from_https(
-                    cfg.clone(),
-                    certs_path,
-                    servers_tracker,
-                    frontend.clone().name,
-                    addr,
-                )
-                .await
-                {
-                    eprintln!("Frontend {} crashed: {}", frontend.name, e);
-                }
-            });
-        } else {
-            // Frontend http
-            server_task = tokio::spawn(async move {
-                let servers_tracker: Arc<arc_swap::ArcSwapAny<Arc<ServerTracker>>> =
-                    Arc::new(ArcSwap::new({
-                        let mut tracker = ServerTracker::new();
-                        tracker.populate(frontend.clone().name, cfg.clone());
-                        Arc::new(tracker)
-                    }));
-
-                if let Err(e) =
-                    proxy_from_http(cfg.clone(), servers_tracker, frontend.clone().name, addr).await
-                {
-                    eprintln!("Frontend {} crashed: {}", frontend.name, e);
-                }
-            });
-        }
-        listeners.push(server_task);
-    }
     // Internal frontend http (hard because i don't know how to implement a fake Response<Incoming> in listeners when backend is disabled
     let ipaddr = parse_bind_address("127.0.0.1").unwrap();
     let addr = SocketAddr::from((ipaddr, HTTP_INTERNAL_SERVER));
-    let server_task: tokio::task::JoinHandle<()>;
 
     let frontend_name = "internal".to_string();
-    server_task = tokio::spawn(async move {
-        if let Err(e) = internal_http(frontend_name.clone(), addr).await {
+    let acme_challenges = AcmeChallenges::default();
+    let challenges = acme_challenges.clone();
+    tokio::spawn(async move {
+        if let Err(e) = internal_http(frontend_name.clone(), addr, challenges).await {
             eprintln!("Frontend {} crashed: {}", frontend_name, e);
         }
     });
-    listeners.push(server_task);
 
-    // Wait for CTRL+C or all servers to exit
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            println!("Shutdown signal received");
+    // Certificates ordered and renewed with ACME, when configured
+    let acme_manager = AcmeManager::new(
+        config.clone(),
+        certs_path,
+        certs_manager.clone(),
+        acme_challenges,
+    );
+    tokio::spawn(acme_manager.watch());
+
+    // Api Rest, only with a token
+    if let Some(api_addr) = api_addr {
+        match api_token {
+            Some(token) if !token.is_empty() => {
+                let state = Arc::new(ApiState {
+                    config_manager: config_manager.clone(),
+                    frontends_manager: frontends_manager.clone(),
+                    certs_manager: certs_manager.clone(),
+                    token,
+                });
+                tokio::spawn(async move {
+                    if let Err(e) = api_rest(state, api_addr).await {
+                        eprintln!("API Rest crashed: {}", e);
+                    }
+                });
+            }
+            _ => {
+                eprintln!("API Rest not started: no token set (--api-token or API_TOKEN)");
+            }
         }
-        _ = async {
-            for server in listeners {
-                let _ = server.await;
+    }
+
+    // Reload config and certificates on SIGHUP or when the files change, until CTRL+C
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut watch = tokio::time::interval(Duration::from_secs(CONFIG_WATCH_INTERVAL));
+    let mut certs_watch = tokio::time::interval(Duration::from_secs(CERTS_WATCH_INTERVAL));
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                println!("Shutdown signal received");
+                break;
+            }
+            _ = sighup.recv() => {
+                println!("SIGHUP received, reloading configuration and certificates");
+                reload_certificates(&certs_manager).await;
+            }
+            _ = watch.tick() => {
+                if !config_manager.lock().await.is_config_modified() {
+                    continue;
+                }
+                println!("Configuration file changed, reloading");
+            }
+            _ = certs_watch.tick() => {
+                if certs_manager.lock().await.is_modified() {
+                    println!("Tls certificates changed, reloading");
+                    reload_certificates(&certs_manager).await;
+                }
+                continue;
+            }
+        }
+        let reloaded = config_manager.lock().await.reload().await;
+        match reloaded {
+            Ok(version) => {
+                frontends_manager.lock().await.apply().await;
+                println!("Configuration reloaded, version: {}", version);
+            }
+            Err(e) => {
+                eprintln!("Configuration reload failed, keeping current: {}", e);
             }
-        } => {
-            println!("All frontend servers terminated");
         }
     }
     Ok(())
 }
+
+/**
+ * Invalid files are rejected, the certificates already served are kept
+ */
+async fn reload_certificates(certs_manager: &Mutex<CertsManager>) {
+    match certs_manager.lock().await.load() {
+        Ok(count) => println!("Tls certificates reloaded: {} certificate(s)", count),
+        Err(e) => eprintln!("Tls certificates reload failed, keeping current: {}", e),
+    }
+}
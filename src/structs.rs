@@ -1,7 +1,16 @@
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::constants::{
+    DEFAULT_ACME_RENEW_BEFORE_DAYS, DEFAULT_HEALTH_CHECK_EXPECTED_STATUS,
+    DEFAULT_HEALTH_CHECK_FALL, DEFAULT_HEALTH_CHECK_INTERVAL, DEFAULT_HEALTH_CHECK_PATH,
+    DEFAULT_HEALTH_CHECK_RISE, DEFAULT_HEALTH_CHECK_TIMEOUT, DEFAULT_PASSIVE_CHECK_EJECTION_TIME,
+    DEFAULT_PASSIVE_CHECK_MAX_EJECTION_TIME, DEFAULT_PASSIVE_CHECK_MAX_FAILURES,
+    DEFAULT_REDIRECT_STATUS, DEFAULT_SERVER_WEIGHT, DEFAULT_STICKY_COOKIE_NAME,
+    DEFAULT_UNKNOWN_HOST_STATUS,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ProxyProtocols {
     Http,
@@ -17,13 +26,136 @@ impl AsRef<str> for ProxyProtocols {
     }
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathMatchType {
+    #[default]
+    Prefix,
+    Exact,
+    Regex,
+}
+
+// Acl path condition, rewrite replaces the matched part before forwarding
+// (regex: $1, $name... are the captures)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathMatch {
+    #[serde(rename = "type", default)]
+    pub match_type: PathMatchType,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<String>,
+}
+
+// Named value of a header, a query parameter or a cookie, any value when value is none
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValueMatch {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+// Acl request condition, composable with all/any/not
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclCondition {
+    All(Vec<AclCondition>),
+    Any(Vec<AclCondition>),
+    Not(Box<AclCondition>),
+    // GET, POST...
+    Method(Vec<String>),
+    Header(ValueMatch),
+    Query(ValueMatch),
+    Cookie(ValueMatch),
+    // client ip in one of the CIDR ranges (or ip)
+    Source(Vec<String>),
+}
+
+// Acl redirect answered by the proxy, {host} {path} and {query} are replaced in "to"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Redirect {
+    pub to: String,
+    // 301 | 302 | 307 | 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    // append the original path and query to "to"
+    #[serde(default)]
+    pub preserve_path: bool,
+}
+
+const fn default_redirect_status() -> u16 {
+    DEFAULT_REDIRECT_STATUS
+}
+
+// Rewriting of the absolute urls of Location, Content-Location and Refresh response headers
+// to the scheme and host of the frontend
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocationRewrite {
+    Off,
+    // only urls targeting a server of the backend
+    #[default]
+    Backend,
+    Always,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderAction {
+    // append a value
+    Add,
+    // replace the values
+    Set,
+    Remove,
+    // value is the new name
+    Rename,
+}
+
+// Header rule, {client_ip} {host} {frontend} {sni} and {request_id} are replaced in value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeaderRule {
+    pub action: HeaderAction,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+// Request headers before forwarding, response headers before returning to the client
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HeaderRules {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub request: Vec<HeaderRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response: Vec<HeaderRule>,
+}
+
 // Acl config
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AclConfig {
     pub name: String,
     pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathMatch>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_yaml::with::singleton_map_recursive"
+    )]
+    pub when: Option<AclCondition>,
+    // not used (may be empty) with redirect
+    #[serde(default)]
     pub backend: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub antibot: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<Redirect>,
+    #[serde(default)]
+    pub rewrite_location: LocationRewrite,
+    // applied after the rules of the backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<HeaderRules>,
+    // tls frontend: the TLS stream of this host (SNI) is sent as is to the backend
+    #[serde(default)]
+    pub passthrough: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,7 +166,166 @@ pub struct FrontEnd {
     pub addr: String,
     pub tls: bool,
     pub active: bool,
+    // http only, tcp frontends use default_backend
+    #[serde(default)]
     pub acls: Vec<AclConfig>,
+    // backend of the requests matching no acl
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_backend: Option<String>,
+    // http status when no acl matches and there is no default backend (404 or 421)
+    #[serde(default = "default_unknown_host_status")]
+    pub unknown_host_status: u16,
+    // name of the https frontend, all the requests are redirected to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub https_redirect: Option<String>,
+    // peers (cidr or address) whose X-Forwarded-* and Forwarded headers are kept
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxies: Vec<String>,
+    // connections start with a PROXY header (v1 or v2) giving the client address
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+    // tls: certificate (file name without .pem) for clients without SNI or with an unknown name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_certificate: Option<String>,
+}
+
+const fn default_unknown_host_status() -> u16 {
+    DEFAULT_UNKNOWN_HOST_STATUS
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckType {
+    Http,
+    Tcp,
+}
+
+const fn default_health_check_type() -> HealthCheckType {
+    HealthCheckType::Tcp
+}
+fn default_health_check_path() -> String {
+    DEFAULT_HEALTH_CHECK_PATH.to_string()
+}
+const fn default_health_check_expected_status() -> u16 {
+    DEFAULT_HEALTH_CHECK_EXPECTED_STATUS
+}
+const fn default_health_check_interval() -> u64 {
+    DEFAULT_HEALTH_CHECK_INTERVAL
+}
+const fn default_health_check_timeout() -> u64 {
+    DEFAULT_HEALTH_CHECK_TIMEOUT
+}
+const fn default_health_check_rise() -> u32 {
+    DEFAULT_HEALTH_CHECK_RISE
+}
+const fn default_health_check_fall() -> u32 {
+    DEFAULT_HEALTH_CHECK_FALL
+}
+
+// Active health check, interval and timeout in seconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    #[serde(rename = "type", default = "default_health_check_type")]
+    pub check_type: HealthCheckType,
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    #[serde(default = "default_health_check_expected_status")]
+    pub expected_status: u16,
+    #[serde(default = "default_health_check_interval")]
+    pub interval: u64,
+    #[serde(default = "default_health_check_timeout")]
+    pub timeout: u64,
+    // consecutive successes to set a server up
+    #[serde(default = "default_health_check_rise")]
+    pub rise: u32,
+    // consecutive failures to set a server down
+    #[serde(default = "default_health_check_fall")]
+    pub fall: u32,
+}
+
+const fn default_passive_check_enabled() -> bool {
+    true
+}
+const fn default_passive_check_max_failures() -> u32 {
+    DEFAULT_PASSIVE_CHECK_MAX_FAILURES
+}
+const fn default_passive_check_ejection_time() -> u64 {
+    DEFAULT_PASSIVE_CHECK_EJECTION_TIME
+}
+const fn default_passive_check_max_ejection_time() -> u64 {
+    DEFAULT_PASSIVE_CHECK_MAX_EJECTION_TIME
+}
+
+// Passive health check: servers are ejected after max_failures consecutive
+// forwarding errors or 5xx, ejection_time doubles on each new ejection (seconds)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PassiveCheck {
+    #[serde(default = "default_passive_check_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_passive_check_max_failures")]
+    pub max_failures: u32,
+    #[serde(default = "default_passive_check_ejection_time")]
+    pub ejection_time: u64,
+    #[serde(default = "default_passive_check_max_ejection_time")]
+    pub max_ejection_time: u64,
+}
+
+impl Default for PassiveCheck {
+    fn default() -> Self {
+        Self {
+            enabled: default_passive_check_enabled(),
+            max_failures: default_passive_check_max_failures(),
+            ejection_time: default_passive_check_ejection_time(),
+            max_ejection_time: default_passive_check_max_ejection_time(),
+        }
+    }
+}
+
+// Load balancing algorithm
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    // fewest requests/websockets in progress
+    LeastConn,
+    // consistent hash on hash_key
+    Hash,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashSource {
+    // client ip address
+    #[default]
+    Ip,
+    Header,
+    Cookie,
+    // uri path
+    Path,
+}
+
+// Key for balance: hash, name is the header or cookie name
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HashKey {
+    #[serde(default)]
+    pub source: HashSource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+fn default_sticky_cookie() -> String {
+    DEFAULT_STICKY_COOKIE_NAME.to_string()
+}
+
+// Sticky sessions: the cookie identifies the server chosen for the first request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StickySession {
+    #[serde(default = "default_sticky_cookie")]
+    pub cookie: String,
+    // seconds, session cookie when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<i64>,
 }
 
 // Backend server
@@ -42,10 +333,26 @@ pub struct FrontEnd {
 pub struct Backend {
     pub name: String,
     pub servers: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_key: Option<HashKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sticky: Option<StickySession>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passive_check: Option<PassiveCheck>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<HeaderRules>,
+}
+
+const fn default_weight() -> u32 {
+    DEFAULT_SERVER_WEIGHT
 }
 
 // Backend server
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BackendServer {
     pub name: String,
     pub host: String, // fqdn | ip address
@@ -53,7 +360,40 @@ pub struct BackendServer {
     pub protocol: ProxyProtocols,
     pub tls: bool, // final endpoing is ssl ???
     pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
+    // share of the traffic, 0: no traffic
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    // PROXY header sent on each connection, with the client address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+const fn default_acme_renew_before_days() -> u64 {
+    DEFAULT_ACME_RENEW_BEFORE_DAYS
+}
+
+// Certificates of the https frontends hosts ordered with ACME (Let's Encrypt...)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AcmeConfig {
+    pub directory_url: String,
+    // "mailto:admin@domain.com"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contact: Vec<String>,
+    // days before expiry to renew the certificates
+    #[serde(default = "default_acme_renew_before_days")]
+    pub renew_before_days: u64,
+    // pem file of the CA of the ACME server, test servers (Pebble...)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_certificate: Option<String>,
 }
 
 // Default value function
@@ -66,9 +406,15 @@ pub struct ProxyConfig {
     pub frontends: Vec<FrontEnd>,
     pub pool_backends: Vec<Backend>,
     pub pool_servers: Vec<BackendServer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acme: Option<AcmeConfig>,
     #[serde(default = "default_version")]
     pub version: u64,
 }
 
 pub type GenericError = Box<dyn Error + Send + Sync + 'static>;
 pub type GenericResult<T> = Result<T, GenericError>;
+
+// TLS server name (SNI) sent by the client, request extension
+#[derive(Debug, Clone)]
+pub struct TlsServerName(pub String);